@@ -1,10 +1,16 @@
+use std::collections::BTreeMap;
 use std::env::args;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::time::Instant;
-use std::fmt::Write as FmtWrite;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use flate2::read::GzDecoder;
+use notify::{RecursiveMode, Watcher};
+use serde_json::{json, Value};
 use sha1::{Digest, Sha1};
-use png;
 
 /// Strip bracketed numeric tags (e.g. [16] [300]) and leading numeric prefixes (e.g. "8. - ")
 /// from a title string.
@@ -24,9 +30,8 @@ fn strip_title_tags(title: &str) -> String {
             }
         } else {
             // Also strip leading numeric prefixes like "8. - "
-            let mut chars = s.char_indices();
             let mut pos = 0;
-            while let Some((i, c)) = chars.next() {
+            for (i, c) in s.char_indices() {
                 if c.is_ascii_digit() || c == '.' {
                     pos = i + c.len_utf8();
                 } else {
@@ -48,6 +53,7 @@ fn strip_title_tags(title: &str) -> String {
 #[derive(Default)]
 struct ArrowStats {
     total_arrows: u32,
+    taps: u32,
     left: u32,
     down: u32,
     up: u32,
@@ -58,6 +64,12 @@ struct ArrowStats {
     mines: u32,
     holds: u32,
     rolls: u32,
+    lifts: u32,
+    fakes: u32,
+    /// Taps/hold heads/roll heads inside `#FAKES` regions.
+    faked_notes: u32,
+    /// Mines directly followed by a note in the same lane.
+    guide_mines: u32,
 }
 
 /// Tracks how many dense measures appear at each run level.
@@ -68,6 +80,12 @@ struct StreamCounts {
     run24_streams: u32,
     run32_streams: u32,
     total_breaks: u32,
+    /// Leading/trailing break measures outside the first..last run span.
+    unused_measures: u32,
+    /// Mine rows inside stream measures or the measures directly bordering them.
+    stream_mines: u32,
+    /// Measures whose notes follow a short-long (gallop) spacing.
+    gallop_measures: u32,
 }
 
 /// A measure’s “density” category.
@@ -86,6 +104,22 @@ enum BreakdownMode {
     Detailed,
     Partial,
     Simplified,
+    /// Detailed, with `[bpm]` markers from `generate_annotated_breakdown`.
+    Annotated,
+}
+
+impl FromStr for BreakdownMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "detailed" => Ok(BreakdownMode::Detailed),
+            "partial" => Ok(BreakdownMode::Partial),
+            "simple" | "simplified" => Ok(BreakdownMode::Simplified),
+            "annotated" => Ok(BreakdownMode::Annotated),
+            _ => Err(format!("Unknown breakdown mode: {}", s)),
+        }
+    }
 }
 
 /// Pattern stats (foot candles, anchors, boxes, etc.).
@@ -112,6 +146,8 @@ struct PatternStats {
     left_dorito: u32,
     inv_right_dorito: u32,
     inv_left_dorito: u32,
+    drills: u32,
+    longest_drill: u32,
 }
 
 // --------------------------------------------------------------------
@@ -124,7 +160,7 @@ fn is_all_zero(line: &[u8; 4]) -> bool {
 }
 
 fn minimize_measure(measure: &mut Vec<[u8; 4]>) {
-    while measure.len() >= 2 && measure.len() % 2 == 0 {
+    while measure.len() >= 2 && measure.len().is_multiple_of(2) {
         if (1..measure.len()).step_by(2).any(|i| !is_all_zero(&measure[i])) {
             break;
         }
@@ -141,11 +177,19 @@ fn minimize_measure(measure: &mut Vec<[u8; 4]>) {
     }
 }
 
+/// Counts one row into `stats`, returning whether it has a step. Holds and
+/// rolls are told apart by their head (`2` vs `4`); the shared `3` tail is
+/// never counted, so a converter's choice of tail can't skew either count.
+/// Lifts (`L`) are tallied on their own and are not steps; keysounds (`K`)
+/// play no arrow and are not counted at all.
 fn count_line(line: &[u8; 4], stats: &mut ArrowStats) -> bool {
     let mut pressed = 0u32;
     for &ch in line {
         match ch {
-            b'1' => pressed += 1,
+            b'1' => {
+                stats.taps += 1;
+                pressed += 1;
+            }
             b'2' => {
                 stats.holds += 1;
                 pressed += 1;
@@ -157,6 +201,12 @@ fn count_line(line: &[u8; 4], stats: &mut ArrowStats) -> bool {
             b'M' => {
                 stats.mines += 1;
             }
+            b'L' => {
+                stats.lifts += 1;
+            }
+            b'F' => {
+                stats.fakes += 1;
+            }
             _ => {}
         }
     }
@@ -189,6 +239,11 @@ fn count_line(line: &[u8; 4], stats: &mut ArrowStats) -> bool {
 }
 
 /// Minimizes chart + counts arrows, returning (final chart bytes, arrow stats, measure densities).
+///
+/// The same pass serves both counting and hashing: each measure is reduced to
+/// its smallest equivalent row resolution, comments/blank lines are dropped, and
+/// rows are cut to 4 columns. Once trailing newlines are trimmed, the returned
+/// bytes are exactly what goes into the chart hash.
 fn minimize_chart_and_count(notes_data: &[u8]) -> (Vec<u8>, ArrowStats, Vec<usize>) {
     let mut output = Vec::with_capacity(notes_data.len());
     let mut measure = Vec::with_capacity(64);
@@ -224,6 +279,8 @@ fn minimize_chart_and_count(notes_data: &[u8]) -> (Vec<u8>, ArrowStats, Vec<usiz
     }
 
     for line in notes_data.split(|&b| b == b'\n') {
+        // tolerate CRLF line endings
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
         if line.is_empty() {
             continue;
         }
@@ -243,6 +300,12 @@ fn minimize_chart_and_count(notes_data: &[u8]) -> (Vec<u8>, ArrowStats, Vec<usiz
             b'/' => {
                 // skip lines starting with a comment
             }
+            b'#' if is_tag_start(line) => {
+                // the `;` was missing and the next tag has started
+                finalize_measure(&mut measure, &mut output, &mut stats, &mut measure_densities);
+                saw_semicolon = true;
+                break;
+            }
             _ => {
                 if line.len() < 4 {
                     // skip malformed lines
@@ -260,31 +323,190 @@ fn minimize_chart_and_count(notes_data: &[u8]) -> (Vec<u8>, ArrowStats, Vec<usiz
     }
 
     // remove trailing ",\n"
-    if output.ends_with(&[b',', b'\n']) {
+    if output.ends_with(b",\n") {
         output.truncate(output.len() - 2);
     }
 
+    // A chart without a single row has no measures at all, rather than one
+    // empty measure; it is still reported, just with zeroed metrics.
+    if output.iter().all(|&b| b == b',' || b == b'\n') {
+        output.clear();
+        measure_densities.clear();
+    }
+
     (output, stats, measure_densities)
 }
 
+/// Minimum notes per measure for the 16th, 20th, 24th and 32nd run levels, ascending.
+type StreamThresholds = [usize; 4];
+
+const DEFAULT_STREAM_THRESHOLDS: StreamThresholds = [16, 20, 24, 32];
+
 #[inline]
-fn categorize_measure_density(d: usize) -> RunDensity {
+fn categorize_measure_density(d: usize, thresholds: &StreamThresholds) -> RunDensity {
     match d {
-        d if d >= 32 => RunDensity::Run32,
-        d if d >= 24 => RunDensity::Run24,
-        d if d >= 20 => RunDensity::Run20,
-        d if d >= 16 => RunDensity::Run16,
+        d if d >= thresholds[3] => RunDensity::Run32,
+        d if d >= thresholds[2] => RunDensity::Run24,
+        d if d >= thresholds[1] => RunDensity::Run20,
+        d if d >= thresholds[0] => RunDensity::Run16,
         _ => RunDensity::Break,
     }
 }
 
-fn compute_stream_counts(measure_densities: &[usize]) -> StreamCounts {
+/// Keeps measures `start..end` of a minimized chart, in the same format.
+fn select_measures(minimized_chart: &[u8], start: usize, end: usize) -> Vec<u8> {
+    let measures: Vec<&[u8]> = minimized_chart
+        .split(|&b| b == b',')
+        .map(|measure| measure.strip_prefix(b"\n").unwrap_or(measure))
+        .map(|measure| measure.strip_suffix(b"\n").unwrap_or(measure))
+        .collect();
+    measures
+        .get(start..end)
+        .unwrap_or_default()
+        .join(&b"\n,\n"[..])
+}
+
+/// Counts the rows containing at least one mine in each measure of a minimized chart.
+fn compute_measure_mine_rows(minimized_chart: &[u8]) -> Vec<u32> {
+    let mut mine_rows = vec![0u32];
+    for line in minimized_chart.split(|&b| b == b'\n') {
+        if line.first() == Some(&b',') {
+            mine_rows.push(0);
+        } else if line.contains(&b'M') {
+            *mine_rows.last_mut().unwrap() += 1;
+        }
+    }
+    mine_rows
+}
+
+/// Counts the notes (taps, hold and roll heads) in each lane of each measure of
+/// a minimized chart, indexed as `heatmap[lane][measure]`. The lane count is
+/// taken from the chart's row width.
+fn compute_playfield_heatmap(minimized_chart: &[u8], measure_count: usize) -> Vec<Vec<u32>> {
+    let lanes = minimized_chart
+        .split(|&b| b == b'\n')
+        .find(|line| !line.is_empty() && line[0] != b',')
+        .map_or(0, |line| line.len());
+    let mut heatmap = vec![vec![0u32; measure_count]; lanes];
+    let mut measure = 0;
+    for line in minimized_chart.split(|&b| b == b'\n') {
+        if line.first() == Some(&b',') {
+            measure += 1;
+            continue;
+        }
+        for (lane, &ch) in line.iter().enumerate().take(lanes) {
+            if matches!(ch, b'1' | b'2' | b'4') && measure < measure_count {
+                heatmap[lane][measure] += 1;
+            }
+        }
+    }
+    heatmap
+}
+
+/// What happens in one measure, for drawing a per-measure timeline.
+struct MeasureProfile {
+    /// Measure number in the whole chart.
+    index: usize,
+    nps: f64,
+    is_stream: bool,
+    /// Note rows that repeat an arrow from the note row before them, which
+    /// may be in the previous measure.
+    jacks: u32,
+}
+
+fn compute_measure_profiles(
+    minimized_chart: &[u8],
+    measure_densities: &[usize],
+    measure_nps_vec: &[f64],
+    thresholds: &StreamThresholds,
+    first_measure: usize,
+) -> Vec<MeasureProfile> {
+    let mut jacks = vec![0u32; measure_densities.len()];
+    let mut measure = 0;
+    let mut prev_mask = 0u8;
+    for line in minimized_chart.split(|&b| b == b'\n') {
+        if line.first() == Some(&b',') {
+            measure += 1;
+            continue;
+        }
+        if line.len() < 4 {
+            continue;
+        }
+        let mask = line_to_bitmask(line);
+        if mask == 0 {
+            continue;
+        }
+        if mask & prev_mask != 0 && measure < jacks.len() {
+            jacks[measure] += 1;
+        }
+        prev_mask = mask;
+    }
+    measure_densities
+        .iter()
+        .zip(measure_nps_vec)
+        .zip(jacks)
+        .enumerate()
+        .map(|(i, ((&density, &nps), jacks))| MeasureProfile {
+            index: first_measure + i,
+            nps,
+            is_stream: categorize_measure_density(density, thresholds) != RunDensity::Break,
+            jacks,
+        })
+        .collect()
+}
+
+/// Counts measures played as a gallop: the gaps between consecutive notes
+/// alternate between a short and a longer one (e.g. 16th then dotted 8th),
+/// repeated at least twice. Gaps are in rows of the minimized measure, whose
+/// rows are evenly spaced.
+fn count_gallop_measures(minimized_chart: &[u8]) -> u32 {
+    let mut gallops = 0;
+    for measure in minimized_chart.split(|&b| b == b',') {
+        let note_rows: Vec<usize> = measure
+            .split(|&b| b == b'\n')
+            .filter(|line| line.len() >= 4)
+            .enumerate()
+            .filter(|(_, line)| line.iter().any(|&c| matches!(c, b'1' | b'2' | b'4')))
+            .map(|(row, _)| row)
+            .collect();
+        let gaps: Vec<usize> = note_rows.windows(2).map(|w| w[1] - w[0]).collect();
+        if gaps.len() >= 3
+            && gaps[0] < gaps[1]
+            && gaps.iter().enumerate().all(|(i, &gap)| gap == gaps[i % 2])
+        {
+            gallops += 1;
+        }
+    }
+    gallops
+}
+
+/// Counts mine rows that sit in or right next to a stream measure, which
+/// separates a real minefield from a few decorative mines.
+fn count_stream_mines(
+    measure_densities: &[usize],
+    measure_mine_rows: &[u32],
+    thresholds: &StreamThresholds,
+) -> u32 {
+    let is_stream = |i: usize| {
+        measure_densities
+            .get(i)
+            .is_some_and(|&d| categorize_measure_density(d, thresholds) != RunDensity::Break)
+    };
+    measure_mine_rows
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| is_stream(i) || (i > 0 && is_stream(i - 1)) || is_stream(i + 1))
+        .map(|(_, &rows)| rows)
+        .sum()
+}
+
+fn compute_stream_counts(measure_densities: &[usize], thresholds: &StreamThresholds) -> StreamCounts {
     let mut sc = StreamCounts::default();
 
     // First, convert measures to their density category
     let cats: Vec<RunDensity> = measure_densities
         .iter()
-        .map(|&d| categorize_measure_density(d))
+        .map(|&d| categorize_measure_density(d, thresholds))
         .collect();
 
     // Find the first measure that isn't a break
@@ -292,8 +514,9 @@ fn compute_stream_counts(measure_densities: &[usize]) -> StreamCounts {
     // Find the last measure that isn't a break
     let last_run  = cats.iter().rposition(|&c| c != RunDensity::Break);
 
-    // If everything is a break (or empty), just return defaults
+    // If everything is a break (or empty), every measure is unused
     if first_run.is_none() || last_run.is_none() {
+        sc.unused_measures = cats.len() as u32;
         return sc;
     }
 
@@ -310,6 +533,18 @@ fn compute_stream_counts(measure_densities: &[usize]) -> StreamCounts {
             RunDensity::Break => sc.total_breaks += 1,
         }
     }
+    sc.unused_measures = (start_idx + (cats.len() - 1 - end_idx)) as u32;
+
+    debug_assert_eq!(
+        cats.len() as u32,
+        sc.run16_streams
+            + sc.run20_streams
+            + sc.run24_streams
+            + sc.run32_streams
+            + sc.total_breaks
+            + sc.unused_measures,
+        "stream + break + unused measures must add up to the total measure count"
+    );
 
     sc
 }
@@ -325,6 +560,38 @@ enum Token {
     Break(usize),           // e.g. (5)
 }
 
+/// A stretch of consecutive stream measures, or the break between two such
+/// stretches. Measure indices are inclusive.
+struct StreamSequence {
+    stream_start: usize,
+    stream_end: usize,
+    is_break: bool,
+}
+
+/// The run/break boundaries behind the breakdown strings, without the
+/// leading and trailing breaks. `first_measure` is the index of
+/// `measure_densities[0]` in the whole chart.
+fn compute_stream_sequences(
+    measure_densities: &[usize],
+    thresholds: &StreamThresholds,
+    first_measure: usize,
+) -> Vec<StreamSequence> {
+    let mut sequences: Vec<StreamSequence> = Vec::new();
+    for (i, &d) in measure_densities.iter().enumerate() {
+        let is_break = categorize_measure_density(d, thresholds) == RunDensity::Break;
+        let measure = first_measure + i;
+        match sequences.last_mut() {
+            Some(last) if last.is_break == is_break => last.stream_end = measure,
+            None if is_break => {}
+            _ => sequences.push(StreamSequence { stream_start: measure, stream_end: measure, is_break }),
+        }
+    }
+    if sequences.last().is_some_and(|s| s.is_break) {
+        sequences.pop();
+    }
+    sequences
+}
+
 fn format_run_symbol(cat: RunDensity, length: usize, star: bool) -> String {
     let base = match cat {
         RunDensity::Run16 => format!("{}", length),
@@ -341,11 +608,15 @@ fn format_run_symbol(cat: RunDensity, length: usize, star: bool) -> String {
 }
 
 /// Build and merge tokens according to the breakdown mode.
-fn generate_breakdown(measure_densities: &[usize], mode: BreakdownMode) -> String {
+fn generate_breakdown(
+    measure_densities: &[usize],
+    mode: BreakdownMode,
+    thresholds: &StreamThresholds,
+) -> String {
     // 1) categorize
     let cats: Vec<RunDensity> = measure_densities
         .iter()
-        .map(|&d| categorize_measure_density(d))
+        .map(|&d| categorize_measure_density(d, thresholds))
         .collect();
 
     // 2) skip leading/trailing breaks
@@ -386,7 +657,7 @@ fn generate_breakdown(measure_densities: &[usize], mode: BreakdownMode) -> Strin
     let threshold = match mode {
         BreakdownMode::Partial => 1,
         BreakdownMode::Simplified => 4,
-        BreakdownMode::Detailed => 0, // for clarity
+        BreakdownMode::Detailed | BreakdownMode::Annotated => 0, // for clarity
     };
 
     while idx < tokens.len() {
@@ -394,7 +665,7 @@ fn generate_breakdown(measure_densities: &[usize], mode: BreakdownMode) -> Strin
             Token::Run(curr_cat, mut curr_len) => {
                 let mut star = false;
 
-                if mode != BreakdownMode::Detailed {
+                if !matches!(mode, BreakdownMode::Detailed | BreakdownMode::Annotated) {
                     'merge_loop: loop {
                         if idx + 1 >= tokens.len() {
                             break;
@@ -450,7 +721,7 @@ fn generate_breakdown(measure_densities: &[usize], mode: BreakdownMode) -> Strin
             }
             Token::Break(bk_len) => {
                 match mode {
-                    BreakdownMode::Detailed => {
+                    BreakdownMode::Detailed | BreakdownMode::Annotated => {
                         if bk_len > 1 {
                             output.push(format!("({})", bk_len));
                         }
@@ -484,20 +755,152 @@ fn generate_breakdown(measure_densities: &[usize], mode: BreakdownMode) -> Strin
     output.join(" ")
 }
 
+/// Formats a BPM for display, dropping a fractional part that rounds away.
+/// Warp tempos show as `warp`.
+fn format_bpm(bpm: f64) -> String {
+    if is_warp_bpm(bpm) {
+        return "warp".to_string();
+    }
+    let rounded = (bpm * 1000.0).round() / 1000.0;
+    let s = format!("{:.3}", rounded);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Detailed breakdown with a `[bpm]` marker wherever the tempo changes (and
+/// before the first run), e.g. `[150] 8 (2) 4 [180] 16`. Markers use brackets
+/// because parentheses already denote break lengths. A change during a break
+/// is marked before the next run, so the break keeps its full length and the
+/// measures add up to the same count as the detailed breakdown.
+fn generate_annotated_breakdown(
+    measure_densities: &[usize],
+    measure_beats: &[(f64, f64)],
+    bpm_map: &[(f64, f64)],
+    thresholds: &StreamThresholds,
+) -> String {
+    let cats: Vec<RunDensity> = measure_densities
+        .iter()
+        .map(|&d| categorize_measure_density(d, thresholds))
+        .collect();
+    let (Some(first_run), Some(last_run)) = (
+        cats.iter().position(|&c| c != RunDensity::Break),
+        cats.iter().rposition(|&c| c != RunDensity::Break),
+    ) else {
+        return String::new();
+    };
+
+    let mut output = Vec::new();
+    let flush = |output: &mut Vec<String>, cat: RunDensity, length: usize| {
+        if cat != RunDensity::Break {
+            output.push(format_run_symbol(cat, length, false));
+        } else if length > 1 {
+            output.push(format!("({})", length));
+        }
+    };
+
+    let mut prev_bpm = None;
+    let mut current: Option<(RunDensity, usize)> = None;
+    for (i, &cat) in cats.iter().enumerate().take(last_run + 1).skip(first_run) {
+        let start_beat = measure_beats.get(i).map_or(i as f64 * 4.0, |&(beat, _)| beat);
+        let bpm = get_current_bpm(start_beat, bpm_map);
+        if cat != RunDensity::Break && prev_bpm != Some(bpm) {
+            if let Some((c, len)) = current.take() {
+                flush(&mut output, c, len);
+            }
+            output.push(format!("[{}]", format_bpm(bpm)));
+            prev_bpm = Some(bpm);
+        }
+        current = match current {
+            Some((c, len)) if c == cat => Some((c, len + 1)),
+            Some((c, len)) => {
+                flush(&mut output, c, len);
+                Some((cat, 1))
+            }
+            None => Some((cat, 1)),
+        };
+    }
+    if let Some((c, len)) = current {
+        flush(&mut output, c, len);
+    }
+
+    output.join(" ")
+}
+
+// --------------------------------------------------------------------
+// Chart hash
+// --------------------------------------------------------------------
+
+/// The minimized chart with its mines blanked out and minimized again, so
+/// rows that only held mines no longer affect the row spacing. Hashing this
+/// instead of the chart itself gives the same hash to charts that differ
+/// only in their mines.
+fn chart_without_mines(minimized_chart: &[u8]) -> Vec<u8> {
+    let blanked: Vec<u8> = minimized_chart
+        .iter()
+        .map(|&b| if b == b'M' { b'0' } else { b })
+        .collect();
+    let mut chart = minimize_chart_and_count(&blanked).0;
+    if let Some(pos) = chart.iter().rposition(|&b| b != b'\n') {
+        chart.truncate(pos + 1);
+    }
+    chart
+}
+
+/// The GrooveStats / ITGmania chart hash, as full lowercase SHA-1 hex.
+///
+/// SHA-1 over, with nothing in between:
+/// 1. the minimized note data from `minimize_chart_and_count`, rows as 4 chars
+///    plus `\n`, measures separated by `,\n`, trailing newlines trimmed;
+/// 2. the BPMs from `normalize_float_digits`: `beat=bpm` pairs, both with
+///    3 decimals, joined by `,` without spaces.
+///
+/// GrooveStats keys charts by the first 16 hex characters. Its note data
+/// keeps mines; `--hash-ignore-mines` hashes `chart_without_mines` instead,
+/// which no longer matches GrooveStats.
+fn groovestats_hash(minimized_chart: &[u8], normalized_bpms: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(minimized_chart);
+    hasher.update(normalized_bpms.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// A fingerprint of just the steps' rhythm and columns, for finding the same
+/// chart re-rated, relabeled or re-timed across packs. First 16 hex chars of
+/// the SHA-1 over one `measure:numerator/denominator:mask;` entry per row with
+/// a tap, hold head or roll head, where the row's place in its measure is a
+/// reduced fraction (so row resolution doesn't matter) and `mask` is its
+/// `line_to_bitmask`. BPMs, timing, metadata, mines, lifts, fakes, and the
+/// difference between taps, holds and rolls are all left out.
+fn rhythm_fingerprint(minimized_chart: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    for (measure, lines) in minimized_chart.split(|&b| b == b',').enumerate() {
+        let rows: Vec<&[u8]> = lines
+            .split(|&b| b == b'\n')
+            .filter(|line| line.len() >= 4)
+            .collect();
+        for (index, row) in rows.iter().enumerate() {
+            let mask = line_to_bitmask(row);
+            if mask == 0 {
+                continue;
+            }
+            let divisor = gcd(index, rows.len());
+            let entry = format!("{}:{}/{}:{};", measure, index / divisor, rows.len() / divisor, mask);
+            hasher.update(entry.as_bytes());
+        }
+    }
+    hex::encode(hasher.finalize())[..16].to_string()
+}
+
 // --------------------------------------------------------------------
 // BPM utilities
 // --------------------------------------------------------------------
 
+/// Normalizes a `#BPMS`-style list to `beat=value` pairs with 3 decimals.
+///
+/// If several entries land on the same (rounded) beat, the last one wins and
+/// takes the first one's place, matching ITGmania, so the hash stays stable.
 fn normalize_float_digits(param: &str) -> String {
-    let mut output = String::with_capacity(param.len());
-    let mut first = true;
+    let mut entries: Vec<(Option<f64>, String)> = Vec::new();
     for beat_bpm in param.split(',').map(str::trim).filter(|s| !s.is_empty()) {
-        if !first {
-            output.push(',');
-        } else {
-            first = false;
-        }
-
         let mut eq_split = beat_bpm.split('=');
         let beat_str = eq_split.next().unwrap_or("").trim_matches(|c: char| c.is_control());
         let bpm_str  = eq_split.next().unwrap_or("").trim_matches(|c: char| c.is_control());
@@ -505,14 +908,45 @@ fn normalize_float_digits(param: &str) -> String {
         if let (Ok(beat_val), Ok(bpm_val)) = (beat_str.parse::<f64>(), bpm_str.parse::<f64>()) {
             let beat_rounded = (beat_val * 1000.0).round() / 1000.0;
             let bpm_rounded  = (bpm_val * 1000.0).round() / 1000.0;
-            let _ = write!(&mut output, "{:.3}={:.3}", beat_rounded, bpm_rounded);
+            let entry = format!("{:.3}={:.3}", beat_rounded, bpm_rounded);
+            match entries.iter_mut().find(|(beat, _)| *beat == Some(beat_rounded)) {
+                Some(existing) => existing.1 = entry,
+                None => entries.push((Some(beat_rounded), entry)),
+            }
         } else {
-            output.push_str(beat_bpm);
+            entries.push((None, beat_bpm.to_string()));
+        }
+    }
+
+    let mut output = String::with_capacity(param.len());
+    for (i, (_, entry)) in entries.iter().enumerate() {
+        if i > 0 {
+            output.push(',');
         }
+        output.push_str(entry);
     }
     output
 }
 
+/// Drops BPM segments that repeat the previous segment's value, so
+/// `0.000=120.000,4.000=120.000` becomes `0.000=120.000`. ITGmania's timing
+/// data never keeps such a segment, so its hashes use the merged list too.
+/// Takes the output of `normalize_float_digits`, whose values are already
+/// formatted alike.
+fn coalesce_bpm_segments(normalized_bpms: &str) -> String {
+    let mut kept = Vec::new();
+    let mut prev_value = None;
+    for segment in normalized_bpms.split(',') {
+        let value = segment.split_once('=').map(|(_, value)| value);
+        if value.is_some() && value == prev_value {
+            continue;
+        }
+        prev_value = value;
+        kept.push(segment);
+    }
+    kept.join(",")
+}
+
 fn parse_bpm_map(normalized_bpms: &str) -> Vec<(f64, f64)> {
     let mut bpms_vec = Vec::new();
     for chunk in normalized_bpms.split(',') {
@@ -521,7 +955,9 @@ fn parse_bpm_map(normalized_bpms: &str) -> Vec<(f64, f64)> {
             let left = &chunk[..eq_pos].trim();
             let right = &chunk[eq_pos + 1..].trim();
             if let (Ok(beat), Ok(bpm)) = (left.parse::<f64>(), right.parse::<f64>()) {
-                bpms_vec.push((beat, bpm));
+                if beat.is_finite() && bpm.is_finite() {
+                    bpms_vec.push((beat, bpm));
+                }
             }
         }
     }
@@ -549,7 +985,8 @@ fn compute_bpm_range(bpm_map: &[(f64, f64)]) -> (i32, i32) {
     }
     let mut min_bpm = f64::MAX;
     let mut max_bpm = f64::MIN;
-    for &(_, bpm) in bpm_map {
+    // Warps skip beats rather than set a tempo anyone plays at.
+    for &(_, bpm) in bpm_map.iter().filter(|&&(_, bpm)| !is_warp_bpm(bpm)) {
         if bpm < min_bpm {
             min_bpm = bpm;
         }
@@ -557,6 +994,9 @@ fn compute_bpm_range(bpm_map: &[(f64, f64)]) -> (i32, i32) {
             max_bpm = bpm;
         }
     }
+    if min_bpm > max_bpm {
+        return (0, 0);
+    }
     // Use round() for standard rounding:
     (
         min_bpm.round() as i32,
@@ -564,47 +1004,444 @@ fn compute_bpm_range(bpm_map: &[(f64, f64)]) -> (i32, i32) {
     )
 }
 
+// --------------------------------------------------------------------
+// Time signatures
+// --------------------------------------------------------------------
+
+/// Parses `#TIMESIGNATURES` (`beat=numerator=denominator,...`) into
+/// (start beat, beats per measure) pairs, sorted by beat.
+fn parse_time_signatures(raw: &str) -> Vec<(f64, f64)> {
+    let mut sigs = Vec::new();
+    for chunk in raw.split(',') {
+        let mut parts = chunk.split('=').map(str::trim);
+        let (Some(beat), Some(num), Some(den)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        if let (Ok(beat), Ok(num), Ok(den)) = (beat.parse::<f64>(), num.parse::<f64>(), den.parse::<f64>()) {
+            if num > 0.0 && den > 0.0 {
+                sigs.push((beat, num * 4.0 / den));
+            }
+        }
+    }
+    sigs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    sigs
+}
+
+/// Parses `#STOPS`/`#DELAYS` (`beat=seconds,...`), sorted by beat. Zero-length
+/// entries such as `0.000=0.000` are dropped, as the engine ignores them.
+fn parse_pauses(raw: &str) -> Vec<(f64, f64)> {
+    parse_bpm_map(raw)
+        .into_iter()
+        .filter(|&(_, seconds)| seconds != 0.0)
+        .collect()
+}
+
+/// Tempo given to the beats a warp skips, so they take no time at all.
+const WARP_SEGMENT_BPM: f64 = f64::INFINITY;
+
+/// Rewrites the legacy warp forms, negative BPMs and negative stops, as
+/// segments at `WARP_SEGMENT_BPM` over the beats they skip (the same way
+/// StepMania converts them). Returns the BPM map and drops the negative stops.
+///
+/// A negative stop skips the beats its length would cover at the current
+/// tempo. A negative BPM runs time backwards until its segment ends, and the
+/// following tempo then needs as long again to catch up, so the skipped beats
+/// extend past the segment by that much.
+fn resolve_negative_warps(bpm_map: Vec<(f64, f64)>, stops: &mut Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    let positive: Vec<(f64, f64)> = bpm_map.iter().copied().filter(|&(_, bpm)| bpm >= 0.0).collect();
+    let mut warps: Vec<(f64, f64)> = Vec::new();
+    for (i, &(start, bpm)) in bpm_map.iter().enumerate() {
+        if bpm >= 0.0 {
+            continue;
+        }
+        let Some(&(end, next_bpm)) = bpm_map.get(i + 1) else { continue };
+        if next_bpm > 0.0 {
+            warps.push((start, end + (end - start) * next_bpm / -bpm));
+        }
+    }
+    for &(beat, seconds) in stops.iter().filter(|&&(_, seconds)| seconds < 0.0) {
+        warps.push((beat, beat - seconds * get_current_bpm(beat, &positive) / 60.0));
+    }
+    stops.retain(|&(_, seconds)| seconds > 0.0);
+    if warps.is_empty() {
+        return bpm_map;
+    }
+
+    // Merge overlapping warps, then lay them over the positive tempos.
+    warps.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = Vec::new();
+    for (start, end) in warps {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    let in_warp = |beat: f64| merged.iter().any(|&(start, end)| beat >= start && beat < end);
+    let mut resolved: Vec<(f64, f64)> = positive.iter().copied().filter(|&(beat, _)| !in_warp(beat)).collect();
+    for &(start, end) in &merged {
+        resolved.push((start, WARP_SEGMENT_BPM));
+        resolved.push((end, get_current_bpm(end, &positive)));
+    }
+    resolved.sort_by(|a, b| a.0.total_cmp(&b.0));
+    resolved
+}
+
+/// Parses `#DISPLAYBPM` as `bpm` or `min:max`. The random `*` and anything
+/// else without numbers give `None`, leaving the chart's own BPMs to show.
+fn parse_display_bpm(raw: &str) -> Option<(f64, f64)> {
+    let mut values = raw.split(':').map(|v| v.trim().parse::<f64>().ok().filter(|v| v.is_finite()));
+    let min = values.next()??;
+    let max = values.next().unwrap_or(Some(min))?;
+    Some((min.min(max), min.max(max)))
+}
+
+/// Raw values of the timing tags, as written in the simfile.
+struct TimingTags<'a> {
+    /// Normalized `#BPMS`.
+    bpms: &'a str,
+    time_signatures: &'a str,
+    fakes: &'a str,
+    stops: &'a str,
+    delays: &'a str,
+    /// `#DISPLAYBPM`, empty when the file has none.
+    display_bpm: &'a str,
+}
+
+/// Timing segments needed to place measures in time.
+struct TimingData {
+    /// (beat, bpm) pairs, sorted by beat.
+    bpm_map: Vec<(f64, f64)>,
+    /// (beat, beats per measure) pairs, sorted by beat.
+    time_signatures: Vec<(f64, f64)>,
+    /// (beat, length in beats) regions whose notes are fake.
+    fakes: Vec<(f64, f64)>,
+    /// (beat, seconds) pauses after the row at that beat.
+    stops: Vec<(f64, f64)>,
+    /// (beat, seconds) pauses before the row at that beat.
+    delays: Vec<(f64, f64)>,
+    /// (min, max) BPM the file asks the song wheel to show instead of its own.
+    display_bpm: Option<(f64, f64)>,
+}
+
+impl TimingData {
+    /// Parses every timing tag at once, so call sites name each tag instead of
+    /// passing a row of easily swapped strings.
+    fn parse(tags: &TimingTags) -> TimingData {
+        let mut stops = parse_pauses(tags.stops);
+        let bpm_map = resolve_negative_warps(parse_bpm_map(tags.bpms), &mut stops);
+        TimingData {
+            bpm_map,
+            time_signatures: parse_time_signatures(tags.time_signatures),
+            // #FAKES shares the beat=value shape of #BPMS, with the value being a length in beats.
+            fakes: parse_bpm_map(tags.fakes),
+            stops,
+            delays: parse_pauses(tags.delays),
+            display_bpm: parse_display_bpm(tags.display_bpm),
+        }
+    }
+
+    /// The BPM as the song wheel shows it: rounded whole numbers, `min-max`
+    /// when the tempo changes and a single value when it is constant. A
+    /// `#DISPLAYBPM` with numbers in it replaces the chart's own BPMs.
+    fn display_bpm_string(&self) -> String {
+        let (min_bpm, max_bpm) = match self.display_bpm {
+            Some((min, max)) => (min.round() as i32, max.round() as i32),
+            None => compute_bpm_range(&self.bpm_map),
+        };
+        if min_bpm == max_bpm {
+            min_bpm.to_string()
+        } else {
+            format!("{}-{}", min_bpm, max_bpm)
+        }
+    }
+
+    /// Beats per measure of the time signature active at `beat` (4.0 when none applies).
+    fn beats_per_measure_at(&self, beat: f64) -> f64 {
+        self.time_signatures
+            .iter()
+            .rev()
+            .find(|&&(sig_beat, _)| beat >= sig_beat)
+            .map_or(4.0, |&(_, beats)| beats)
+    }
+
+    /// Maps a time in seconds (from beat 0) to a beat, walking the BPM segments.
+    /// The first BPM applies from beat 0 even if its segment starts later.
+    /// A time inside a stop or delay maps to the beat it pauses on. Returns
+    /// `None` without BPMs or when the time lies past every segment.
+    fn beat_at_second(&self, second: f64) -> Option<f64> {
+        let mut pauses: Vec<(f64, f64)> = self.stops.iter().chain(&self.delays).copied().collect();
+        pauses.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut paused = 0.0;
+        for (beat, seconds) in pauses {
+            let pause_start = self.bpm_second_at_beat(beat) + paused;
+            if second < pause_start {
+                break;
+            }
+            if second < pause_start + seconds {
+                return Some(beat);
+            }
+            paused += seconds;
+        }
+        self.bpm_beat_at_second(second - paused)
+    }
+
+    /// `beat_at_second` for the BPM segments alone.
+    fn bpm_beat_at_second(&self, second: f64) -> Option<f64> {
+        let mut elapsed = 0.0;
+        for (i, &(seg_beat, bpm)) in self.bpm_map.iter().enumerate() {
+            let start = if i == 0 { 0.0 } else { seg_beat };
+            let end = self.bpm_map.get(i + 1).map_or(f64::INFINITY, |&(b, _)| b);
+            if bpm <= 0.0 {
+                continue;
+            }
+            let seg_seconds = (end - start) * 60.0 / bpm;
+            if elapsed + seg_seconds > second {
+                return Some(start + (second - elapsed) * bpm / 60.0);
+            }
+            elapsed += seg_seconds;
+        }
+        None
+    }
+
+    /// Seconds from beat 0 to `beat`, the inverse of `beat_at_second`. Stops on
+    /// earlier rows and delays on this one have already been waited out.
+    fn second_at_beat(&self, beat: f64) -> f64 {
+        // Rows are placed by division, so allow for rounding when matching beats.
+        let stops: f64 = self
+            .stops
+            .iter()
+            .filter(|&&(stop_beat, _)| stop_beat < beat - 1e-9)
+            .map(|&(_, seconds)| seconds)
+            .sum();
+        let delays: f64 = self
+            .delays
+            .iter()
+            .filter(|&&(delay_beat, _)| delay_beat < beat + 1e-9)
+            .map(|&(_, seconds)| seconds)
+            .sum();
+        self.bpm_second_at_beat(beat) + stops + delays
+    }
+
+    /// `second_at_beat` for the BPM segments alone.
+    fn bpm_second_at_beat(&self, beat: f64) -> f64 {
+        let mut elapsed = 0.0;
+        for (i, &(seg_beat, bpm)) in self.bpm_map.iter().enumerate() {
+            let start = if i == 0 { 0.0 } else { seg_beat };
+            if beat <= start {
+                break;
+            }
+            let end = self.bpm_map.get(i + 1).map_or(f64::INFINITY, |&(b, _)| b);
+            if bpm > 0.0 {
+                elapsed += (end.min(beat) - start) * 60.0 / bpm;
+            }
+        }
+        elapsed
+    }
+
+    /// Seconds of stops and delays on rows from `start` up to (not including) `end`.
+    fn pause_seconds_between(&self, start: f64, end: f64) -> f64 {
+        self.stops
+            .iter()
+            .chain(&self.delays)
+            .filter(|&&(beat, _)| beat >= start && beat < end)
+            .map(|&(_, seconds)| seconds)
+            .sum()
+    }
+
+    /// Lays the measures out back to back, returning (start beat, length in beats)
+    /// for each one.
+    fn measure_beats(&self, num_measures: usize) -> Vec<(f64, f64)> {
+        let mut measure_beats = Vec::with_capacity(num_measures);
+        let mut beat = 0.0;
+        for _ in 0..num_measures {
+            let beats = self.beats_per_measure_at(beat);
+            measure_beats.push((beat, beats));
+            beat += beats;
+        }
+        measure_beats
+    }
+}
+
 // --------------------------------------------------------------------
 // Chart length (in seconds, int).
 // --------------------------------------------------------------------
 
-fn compute_total_chart_length(measure_densities: &[usize], bpm_map: &[(f64, f64)]) -> i32 {
+fn compute_total_chart_length(measure_beats: &[(f64, f64)], timing: &TimingData) -> i32 {
     let mut total_length_seconds = 0.0;
-    for (i, _) in measure_densities.iter().enumerate() {
-        let measure_start_beat = i as f64 * 4.0;
-        let curr_bpm = get_current_bpm(measure_start_beat, bpm_map);
+    for &(measure_start_beat, beats) in measure_beats {
+        // Stops and delays within the measure add to its length.
+        total_length_seconds += timing.pause_seconds_between(measure_start_beat, measure_start_beat + beats);
+        let curr_bpm = get_current_bpm(measure_start_beat, &timing.bpm_map);
         if curr_bpm <= 0.0 {
             continue;
         }
-        let measure_length_s = (4.0 / curr_bpm) * 60.0;
+        let measure_length_s = (beats / curr_bpm) * 60.0;
         total_length_seconds += measure_length_s;
     }
     total_length_seconds.floor() as i32
 }
 
+// --------------------------------------------------------------------
+// Orphan tail notes
+// --------------------------------------------------------------------
+
+/// Empty measures that have to separate the last notes from the rest of the
+/// chart before they count as stray.
+const ORPHAN_TAIL_GAP_MEASURES: usize = 16;
+/// Most notes the stray group can have; more than this is a real ending.
+const ORPHAN_TAIL_MAX_NOTES: usize = 8;
+
+/// Looks for a few notes sitting long after the rest of the chart, usually an
+/// editing mistake that stretches the chart length. The main body ends at the
+/// last measure that leaves more than `ORPHAN_TAIL_MAX_NOTES` notes from there
+/// on; returns the length in beats of the longest empty gap after it.
+fn find_orphan_tail(measure_densities: &[usize], measure_beats: &[(f64, f64)]) -> Option<f64> {
+    let last = measure_densities.iter().rposition(|&d| d > 0)?;
+    let mut tail_notes = 0;
+    let body_end = (0..=last).rev().find(|&i| {
+        tail_notes += measure_densities[i];
+        tail_notes > ORPHAN_TAIL_MAX_NOTES
+    })?;
+
+    let mut longest_gap = (0, 0);
+    let mut gap_start = None;
+    for (i, &density) in measure_densities.iter().enumerate().take(last + 1).skip(body_end + 1) {
+        if density == 0 {
+            gap_start.get_or_insert(i);
+        } else if let Some(start) = gap_start.take() {
+            if i - start > longest_gap.1 - longest_gap.0 {
+                longest_gap = (start, i);
+            }
+        }
+    }
+    let (gap_start, gap_end) = longest_gap;
+    if gap_end - gap_start < ORPHAN_TAIL_GAP_MEASURES {
+        return None;
+    }
+    Some(measure_beats.get(gap_start..gap_end)?.iter().map(|&(_, beats)| beats).sum())
+}
+
 // --------------------------------------------------------------------
 // NPS calculations
 // --------------------------------------------------------------------
 
+/// BPMs at or above this are warps rather than a tempo anyone plays at
+/// (files fake an instant skip with values like `999999`).
+const WARP_BPM_THRESHOLD: f64 = 10_000.0;
+
+/// Whether a segment at `bpm` is a warp, either a huge tempo or the
+/// `WARP_SEGMENT_BPM` that negative BPMs and stops resolve to.
+fn is_warp_bpm(bpm: f64) -> bool {
+    bpm >= WARP_BPM_THRESHOLD
+}
+
 /// Computes a per-measure NPS vector (notes-per-second) from measure densities.
-fn compute_measure_nps_vec(measure_densities: &[usize], bpm_map: &[(f64, f64)]) -> Vec<f64> {
+/// Measures that start inside a warp get 0, like measures without a tempo.
+fn compute_measure_nps_vec(
+    measure_densities: &[usize],
+    measure_beats: &[(f64, f64)],
+    bpm_map: &[(f64, f64)],
+) -> Vec<f64> {
     let mut measure_nps_vec = Vec::with_capacity(measure_densities.len());
-    for (i, &density) in measure_densities.iter().enumerate() {
-        let measure_start_beat = i as f64 * 4.0;
+    for (&density, &(measure_start_beat, beats)) in measure_densities.iter().zip(measure_beats) {
         let curr_bpm = get_current_bpm(measure_start_beat, bpm_map);
-        if curr_bpm <= 0.0 {
+        if curr_bpm <= 0.0 || is_warp_bpm(curr_bpm) {
             measure_nps_vec.push(0.0);
             continue;
         }
         // measure_nps = (notes in measure) / measure duration
-        // measure duration = 4 beats / curr_bpm => * 60 for sec => so 4/curr_bpm*60.
-        // dividing density by measure length => density / (4/curr_bpm*60) => density*(curr_bpm/4)/60
-        let measure_nps = density as f64 * (curr_bpm / 4.0) / 60.0;
+        // measure duration = beats / curr_bpm => * 60 for sec => so beats/curr_bpm*60.
+        // dividing density by measure length => density*(curr_bpm/beats)/60
+        let measure_nps = density as f64 * (curr_bpm / beats) / 60.0;
         measure_nps_vec.push(measure_nps);
     }
     measure_nps_vec
 }
 
+/// Beats the final measure actually spans: one beat past its last note, and
+/// never more than the whole measure. The note's beat comes from its row's
+/// place in the measure, so it is the same however many rows the measure is
+/// written with. Charts often end a beat into their last measure, and
+/// averaging those notes over the full measure would understate its NPS.
+fn final_measure_span(notes_data: &[u8], beats: f64) -> f64 {
+    let mut rows = 0usize;
+    let mut last_note = None;
+    for line in notes_data.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match line.first() {
+            Some(b',') => {
+                rows = 0;
+                last_note = None;
+            }
+            Some(b';') => break,
+            Some(b'#') if is_tag_start(line) => break,
+            Some(b' ') | Some(b'/') | None => {}
+            Some(_) if line.len() >= 4 => {
+                if line[..4].iter().any(|&c| matches!(c, b'1' | b'2' | b'4')) {
+                    last_note = Some(rows);
+                }
+                rows += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    match last_note {
+        Some(index) => (beats * index as f64 / rows as f64 + 1.0).min(beats),
+        None => beats,
+    }
+}
+
+/// Samples the NPS of the measure playing at `second`. Like the other reported
+/// seconds this is song time, so `offset` (the `#OFFSET` value) shifts it onto
+/// the chart. Times before the chart starts or after its last measure give 0.0.
+fn nps_at_second(
+    second: f64,
+    offset: f64,
+    timing: &TimingData,
+    measure_beats: &[(f64, f64)],
+    measure_nps_vec: &[f64],
+) -> f64 {
+    let chart_second = second + offset;
+    if chart_second < 0.0 {
+        return 0.0;
+    }
+    let Some(beat) = timing.beat_at_second(chart_second) else {
+        return 0.0;
+    };
+    measure_beats
+        .iter()
+        .position(|&(start, beats)| beat >= start && beat < start + beats)
+        .and_then(|i| measure_nps_vec.get(i).copied())
+        .unwrap_or(0.0)
+}
+
+/// Seconds over which stamina fatigue decays to 1/e once the notes stop.
+const STAMINA_DECAY_SECONDS: f64 = 60.0;
+
+/// Peak of a fatigue level that rises towards the NPS being played and decays
+/// with `STAMINA_DECAY_SECONDS`. Sustained density keeps pushing it up while a
+/// short burst barely moves it: 12 NPS held for 3 minutes scores about 11.4,
+/// 16 NPS for 20 seconds about 4.5. Warped measures take no time and are skipped.
+fn compute_stamina_score(
+    measure_nps_vec: &[f64],
+    measure_beats: &[(f64, f64)],
+    bpm_map: &[(f64, f64)],
+) -> f64 {
+    let mut fatigue = 0.0f64;
+    let mut peak = 0.0f64;
+    for (&nps, &(measure_start_beat, beats)) in measure_nps_vec.iter().zip(measure_beats) {
+        let curr_bpm = get_current_bpm(measure_start_beat, bpm_map);
+        if curr_bpm <= 0.0 || is_warp_bpm(curr_bpm) {
+            continue;
+        }
+        let seconds = beats * 60.0 / curr_bpm;
+        let decay = (-seconds / STAMINA_DECAY_SECONDS).exp();
+        fatigue = fatigue * decay + nps * (1.0 - decay);
+        peak = peak.max(fatigue);
+    }
+    peak
+}
+
 /// Returns (max_nps, median_nps) from the measure_nps_vec.
 fn get_nps_stats(measure_nps_vec: &[f64]) -> (f64, f64) {
     let max_nps = if measure_nps_vec.is_empty() {
@@ -616,105 +1453,267 @@ fn get_nps_stats(measure_nps_vec: &[f64]) -> (f64, f64) {
     (max_nps, median_nps)
 }
 
+/// Median NPS over the stream measures only, so intros and breaks don't drag
+/// it down. Stream measures are the ones that aren't a break under `thresholds`.
+fn active_median_nps(measure_nps_vec: &[f64], measure_densities: &[usize], thresholds: &StreamThresholds) -> f64 {
+    let active: Vec<f64> = measure_nps_vec
+        .iter()
+        .zip(measure_densities)
+        .filter(|&(_, &d)| categorize_measure_density(d, thresholds) != RunDensity::Break)
+        .map(|(&nps, _)| nps)
+        .collect();
+    median(&active)
+}
+
 // --------------------------------------------------------------------
 // Extract sections
 // --------------------------------------------------------------------
-fn extract_sections(
-    data: &[u8],
-) -> io::Result<(
-    Option<&[u8]>, // title
-    Option<&[u8]>, // subtitle
-    Option<&[u8]>, // artist
-    Option<&[u8]>, // titletranslit
-    Option<&[u8]>, // subtitletranslit
-    Option<&[u8]>, // artisttranslit
-    Option<&[u8]>, // bpms
-    Option<&[u8]>, // notes
-)> {
-    let mut title = None;
-    let mut subtitle = None;
-    let mut artist = None;
-    let mut titletranslit = None;
-    let mut subtitletranslit = None;
-    let mut artisttranslit = None;
-    let mut bpms = None;
-    let mut notes = None;
 
-    let mut i = 0;
-    while i < data.len() {
-        if title.is_some()
-            && subtitle.is_some()
-            && artist.is_some()
-            && bpms.is_some()
-            && notes.is_some()
-        {
-            break;
-        }
+/// Raw tag contents pulled out of a simfile (borrowed from the input buffer).
+#[derive(Default)]
+struct SimfileSections<'a> {
+    title: Option<&'a [u8]>,
+    subtitle: Option<&'a [u8]>,
+    artist: Option<&'a [u8]>,
+    titletranslit: Option<&'a [u8]>,
+    subtitletranslit: Option<&'a [u8]>,
+    artisttranslit: Option<&'a [u8]>,
+    bpms: Option<&'a [u8]>,
+    time_signatures: Option<&'a [u8]>,
+    offset: Option<&'a [u8]>,
+    sample_start: Option<&'a [u8]>,
+    sample_length: Option<&'a [u8]>,
+    music_length: Option<&'a [u8]>,
+    last_second_hint: Option<&'a [u8]>,
+    fakes: Option<&'a [u8]>,
+    stops: Option<&'a [u8]>,
+    delays: Option<&'a [u8]>,
+    display_bpm: Option<&'a [u8]>,
+    /// Kept verbatim; only passed through to the output.
+    bg_changes: Option<&'a [u8]>,
+    fg_changes: Option<&'a [u8]>,
+    notes: Option<&'a [u8]>,
+    /// The first SSC `#NOTEDATA` chart. When set, `notes` holds just its
+    /// note data.
+    notedata: Option<ChartBlock<'a>>,
+    /// Tags that were missing their `;` and got closed by the next tag or EOF.
+    auto_terminated: Vec<&'static str>,
+}
 
-        #[inline]
-        fn parse_tag<'a>(
-            data: &'a [u8],
-            idx: &mut usize,
-            tag_len: usize
-        ) -> Option<&'a [u8]> {
-            let start_idx = *idx + tag_len;
-            if start_idx > data.len() {
-                return None;
-            }
-            if let Some(end_off) = data[start_idx..].iter().position(|&b| b == b';') {
-                let result = &data[start_idx..start_idx + end_off];
-                *idx = start_idx + end_off + 1;
-                Some(result)
-            } else {
-                None
+/// Returns true if `data` starts with something shaped like `#TAG:`.
+fn is_tag_start(data: &[u8]) -> bool {
+    let name_len = data
+        .iter()
+        .skip(1)
+        .take_while(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        .count();
+    data.first() == Some(&b'#') && name_len > 0 && data.get(name_len + 1) == Some(&b':')
+}
+
+/// Whether any line of `data` starts with a `#TAG:`.
+fn has_any_tag(data: &[u8]) -> bool {
+    data.split(|&b| b == b'\n').any(|line| is_tag_start(line.trim_ascii_start()))
+}
+
+/// Finds where a tag value starting at `start` ends. Returns the end offset and
+/// whether a real `;` terminator was found. A missing `;` is recovered from by
+/// treating the next line-leading `#TAG:` (or EOF) as an implicit terminator,
+/// which is what ITGmania does with hand-edited files.
+fn find_tag_end(data: &[u8], start: usize) -> (usize, bool) {
+    let mut at_line_start = false;
+    for (off, &b) in data[start..].iter().enumerate() {
+        match b {
+            b';' => return (start + off, true),
+            b'\n' => at_line_start = true,
+            b'#' if at_line_start && is_tag_start(&data[start + off..]) => {
+                return (start + off, false);
             }
+            b' ' | b'\t' | b'\r' => {}
+            _ => at_line_start = false,
+        }
+    }
+    (data.len(), false)
+}
+
+#[inline]
+fn parse_tag<'a>(
+    data: &'a [u8],
+    idx: &mut usize,
+    tag: &'static str,
+    auto_terminated: &mut Vec<&'static str>,
+) -> Option<&'a [u8]> {
+    let start_idx = *idx + tag.len();
+    if start_idx > data.len() {
+        return None;
+    }
+    let (end_idx, terminated) = find_tag_end(data, start_idx);
+    if terminated {
+        *idx = end_idx + 1;
+    } else {
+        *idx = end_idx;
+        auto_terminated.push(tag.trim_end_matches(':'));
+    }
+    Some(&data[start_idx..end_idx])
+}
+
+fn extract_sections(data: &[u8]) -> io::Result<SimfileSections<'_>> {
+    let mut sections = SimfileSections::default();
+
+    // Header tags may also follow the first `#NOTES` block, so the whole file
+    // is scanned. SSC files keep all song tags ahead of their `#NOTEDATA`
+    // blocks, and those blocks carry per-chart tags, so that scan stops there.
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != b'#' {
+            i += 1;
+            continue;
         }
 
         let slice = &data[i..];
-        if slice.starts_with(b"#TITLE:") && title.is_none() {
-            title = parse_tag(data, &mut i, b"#TITLE:".len());
+        if slice.starts_with(b"#TITLE:") && sections.title.is_none() {
+            sections.title = parse_tag(data, &mut i, "#TITLE:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#SUBTITLE:") && subtitle.is_none() {
-            subtitle = parse_tag(data, &mut i, b"#SUBTITLE:".len());
+        } else if slice.starts_with(b"#SUBTITLE:") && sections.subtitle.is_none() {
+            sections.subtitle = parse_tag(data, &mut i, "#SUBTITLE:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#ARTIST:") && artist.is_none() {
-            artist = parse_tag(data, &mut i, b"#ARTIST:".len());
+        } else if slice.starts_with(b"#ARTIST:") && sections.artist.is_none() {
+            sections.artist = parse_tag(data, &mut i, "#ARTIST:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#TITLETRANSLIT:") && titletranslit.is_none() {
-            titletranslit = parse_tag(data, &mut i, b"#TITLETRANSLIT:".len());
+        } else if slice.starts_with(b"#TITLETRANSLIT:") && sections.titletranslit.is_none() {
+            sections.titletranslit = parse_tag(data, &mut i, "#TITLETRANSLIT:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#SUBTITLETRANSLIT:") && subtitletranslit.is_none() {
-            subtitletranslit = parse_tag(data, &mut i, b"#SUBTITLETRANSLIT:".len());
+        } else if slice.starts_with(b"#SUBTITLETRANSLIT:") && sections.subtitletranslit.is_none() {
+            sections.subtitletranslit = parse_tag(data, &mut i, "#SUBTITLETRANSLIT:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#ARTISTTRANSLIT:") && artisttranslit.is_none() {
-            artisttranslit = parse_tag(data, &mut i, b"#ARTISTTRANSLIT:".len());
+        } else if slice.starts_with(b"#ARTISTTRANSLIT:") && sections.artisttranslit.is_none() {
+            sections.artisttranslit = parse_tag(data, &mut i, "#ARTISTTRANSLIT:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#BPMS:") && bpms.is_none() {
-            bpms = parse_tag(data, &mut i, b"#BPMS:".len());
+        } else if slice.starts_with(b"#BPMS:") && sections.bpms.is_none() {
+            sections.bpms = parse_tag(data, &mut i, "#BPMS:", &mut sections.auto_terminated);
             continue;
-        } else if slice.starts_with(b"#NOTES:") && notes.is_none() {
+        } else if slice.starts_with(b"#TIMESIGNATURES:") && sections.time_signatures.is_none() {
+            sections.time_signatures = parse_tag(data, &mut i, "#TIMESIGNATURES:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#OFFSET:") && sections.offset.is_none() {
+            sections.offset = parse_tag(data, &mut i, "#OFFSET:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#SAMPLESTART:") && sections.sample_start.is_none() {
+            sections.sample_start = parse_tag(data, &mut i, "#SAMPLESTART:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#SAMPLELENGTH:") && sections.sample_length.is_none() {
+            sections.sample_length = parse_tag(data, &mut i, "#SAMPLELENGTH:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#MUSICLENGTH:") && sections.music_length.is_none() {
+            sections.music_length = parse_tag(data, &mut i, "#MUSICLENGTH:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#LASTSECONDHINT:") && sections.last_second_hint.is_none() {
+            sections.last_second_hint = parse_tag(data, &mut i, "#LASTSECONDHINT:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#FAKES:") && sections.fakes.is_none() {
+            sections.fakes = parse_tag(data, &mut i, "#FAKES:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#STOPS:") && sections.stops.is_none() {
+            sections.stops = parse_tag(data, &mut i, "#STOPS:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#DELAYS:") && sections.delays.is_none() {
+            sections.delays = parse_tag(data, &mut i, "#DELAYS:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#DISPLAYBPM:") && sections.display_bpm.is_none() {
+            sections.display_bpm = parse_tag(data, &mut i, "#DISPLAYBPM:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#BGCHANGES:") && sections.bg_changes.is_none() {
+            sections.bg_changes = parse_tag(data, &mut i, "#BGCHANGES:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#FGCHANGES:") && sections.fg_changes.is_none() {
+            sections.fg_changes = parse_tag(data, &mut i, "#FGCHANGES:", &mut sections.auto_terminated);
+            continue;
+        } else if slice.starts_with(b"#NOTEDATA:") && sections.notes.is_none() {
+            if let Some(chart) = split_notedata(&data[i + b"#NOTEDATA:".len()..]) {
+                sections.notes = Some(chart.notes);
+                sections.notedata = Some(chart);
+                break;
+            }
+        } else if slice.starts_with(b"#NOTES:") && sections.notes.is_none() {
             let start_idx = i + b"#NOTES:".len();
             if start_idx < data.len() {
-                notes = Some(&data[start_idx..]);
+                sections.notes = Some(&data[start_idx..]);
             }
-            break;
+            i = find_tag_end(data, start_idx.min(data.len())).0;
+            continue;
         }
         i += 1;
     }
 
-    Ok((
-        title,
-        subtitle,
-        artist,
-        titletranslit,
-        subtitletranslit,
-        artisttranslit,
-        bpms,
-        notes,
-    ))
+    Ok(sections)
 }
 
-fn split_notes_fields<'a>(notes_block: &'a [u8]) -> (Vec<&'a [u8]>, &'a [u8]) {
+/// Extensions of the video files a `#BGCHANGES` entry can play.
+const VIDEO_EXTENSIONS: [&str; 4] = [".avi", ".mpg", ".mp4", ".webm"];
+
+/// Whether any `#BGCHANGES` entry names a video file. Only the file names are
+/// looked at; the rest of each change is ignored.
+fn has_video_background(bg_changes: &str) -> bool {
+    bg_changes
+        .split(',')
+        .filter_map(|change| change.split('=').nth(1))
+        .map(|file| file.trim().to_ascii_lowercase())
+        .any(|file| VIDEO_EXTENSIONS.iter().any(|ext| file.ends_with(ext)))
+}
+
+/// Chart difficulty slots, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Difficulty {
+    Beginner,
+    Easy,
+    Medium,
+    Hard,
+    Challenge,
+    Edit,
+}
+
+impl TryFrom<&str> for Difficulty {
+    type Error = String;
+
+    /// Strictly parses a difficulty label, accepting the legacy names old
+    /// simfiles use (`Basic`, `Maniac`, `SMANIAC`, ...) case-insensitively.
+    fn try_from(label: &str) -> Result<Self, Self::Error> {
+        match label.trim().to_ascii_lowercase().as_str() {
+            "beginner" | "novice" => Ok(Difficulty::Beginner),
+            "easy" | "basic" | "light" => Ok(Difficulty::Easy),
+            "medium" | "another" | "trick" | "standard" | "difficult" => Ok(Difficulty::Medium),
+            "hard" | "maniac" | "heavy" | "ssr" => Ok(Difficulty::Hard),
+            "challenge" | "smaniac" | "expert" | "oni" => Ok(Difficulty::Challenge),
+            "edit" => Ok(Difficulty::Edit),
+            _ => Err(format!("Unknown difficulty: {}", label.trim())),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Difficulty::Beginner => "Beginner",
+            Difficulty::Easy => "Easy",
+            Difficulty::Medium => "Medium",
+            Difficulty::Hard => "Hard",
+            Difficulty::Challenge => "Challenge",
+            Difficulty::Edit => "Edit",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The canonical name of a difficulty label, or the trimmed label itself when
+/// it isn't one `Difficulty` knows.
+fn normalize_difficulty_label(label: &str) -> String {
+    Difficulty::try_from(label).map_or_else(|_| label.trim().to_owned(), |d| d.to_string())
+}
+
+/// Header used for a bare notes block that doesn't carry its own
+/// step type / description / difficulty / meter / radar values.
+const DEFAULT_NOTES_FIELDS: [&str; 5] = ["dance-single", "", "Edit", "", ""];
+
+fn split_notes_fields(notes_block: &[u8]) -> (Vec<&[u8]>, &[u8]) {
     let mut fields = Vec::with_capacity(5);
     let mut colon_count = 0;
     let mut start = 0;
@@ -729,7 +1728,414 @@ fn split_notes_fields<'a>(notes_block: &'a [u8]) -> (Vec<&'a [u8]>, &'a [u8]) {
             }
         }
     }
-    (fields, &notes_block[notes_block.len()..])
+    (fields, &notes_block[start..])
+}
+
+/// One chart of a simfile.
+#[derive(Clone)]
+struct ChartBlock<'a> {
+    /// Header in `#NOTES` field order: step type, description, difficulty,
+    /// meter and radar values.
+    fields: Vec<&'a [u8]>,
+    notes: &'a [u8],
+    /// The tags of an SSC `#NOTEDATA` block ahead of its `#NOTES`, where a
+    /// chart can set its own timing; empty for `#NOTES` charts.
+    chart_tags: &'a [u8],
+}
+
+impl<'a> ChartBlock<'a> {
+    /// A chart from the text following a `#NOTES:` tag.
+    fn from_notes(notes_block: &'a [u8]) -> Self {
+        let (fields, notes) = split_notes_fields(notes_block);
+        ChartBlock { fields, notes, chart_tags: b"" }
+    }
+
+    /// Header field `i` as trimmed text, or its `DEFAULT_NOTES_FIELDS` value
+    /// when the chart doesn't have one.
+    fn field(&self, i: usize) -> &'a str {
+        match self.fields.get(i) {
+            Some(field) => std::str::from_utf8(field).unwrap_or("").trim(),
+            None => DEFAULT_NOTES_FIELDS[i],
+        }
+    }
+}
+
+impl<'a> SimfileSections<'a> {
+    /// Replaces the song's timing tags with the ones a chart sets itself.
+    fn apply_chart_timing(&mut self, chart: &SimfileSections<'a>) {
+        self.bpms = chart.bpms.or(self.bpms);
+        self.time_signatures = chart.time_signatures.or(self.time_signatures);
+        self.offset = chart.offset.or(self.offset);
+        self.fakes = chart.fakes.or(self.fakes);
+        self.stops = chart.stops.or(self.stops);
+        self.delays = chart.delays.or(self.delays);
+        self.display_bpm = chart.display_bpm.or(self.display_bpm);
+    }
+}
+
+/// Reads an SSC `#NOTEDATA` block, where the chart header is spread over
+/// separate `#STEPSTYPE`, `#DESCRIPTION`, `#DIFFICULTY` and `#METER` tags.
+/// Returns `None` if the block ends before its `#NOTES` tag.
+fn split_notedata(block: &[u8]) -> Option<ChartBlock<'_>> {
+    const HEADER_TAGS: [&[u8]; 4] = [b"#STEPSTYPE:", b"#DESCRIPTION:", b"#DIFFICULTY:", b"#METER:"];
+    let mut fields: Vec<&[u8]> = vec![b""; 5];
+    let mut i = 0;
+    while i < block.len() {
+        let slice = &block[i..];
+        if slice.starts_with(b"#NOTES:") {
+            return Some(ChartBlock {
+                fields,
+                notes: &block[i + b"#NOTES:".len()..],
+                chart_tags: &block[..i],
+            });
+        }
+        if slice.starts_with(b"#NOTEDATA:") {
+            return None;
+        }
+        if let Some((field, tag)) = HEADER_TAGS.iter().enumerate().find(|(_, tag)| slice.starts_with(tag)) {
+            let start = i + tag.len();
+            let (end, terminated) = find_tag_end(block, start);
+            fields[field] = &block[start..end];
+            i = if terminated { end + 1 } else { end };
+            continue;
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Every chart in a simfile, in file order. Files with SSC `#NOTEDATA` blocks
+/// are read by those only.
+fn simfile_charts(data: &[u8]) -> Vec<ChartBlock<'_>> {
+    let ssc = data.windows(b"#NOTEDATA:".len()).any(|w| w == b"#NOTEDATA:");
+    (0..data.len())
+        .filter_map(|i| {
+            let slice = &data[i..];
+            if ssc {
+                slice.strip_prefix(b"#NOTEDATA:").and_then(split_notedata)
+            } else {
+                slice.strip_prefix(b"#NOTES:").map(ChartBlock::from_notes)
+            }
+        })
+        .collect()
+}
+
+/// Index in `charts` of another chart with the same notes as `chart`, like the
+/// autogen copies some packs ship under several difficulties. The notes are
+/// compared minimized, so BPMs and how finely measures are written don't
+/// matter.
+fn find_duplicate_chart(charts: &[ChartBlock], chart: &ChartBlock) -> Option<usize> {
+    let notes = minimize_chart_and_count(chart.notes).0;
+    charts
+        .iter()
+        .position(|other| !std::ptr::eq(other.notes, chart.notes) && minimize_chart_and_count(other.notes).0 == notes)
+}
+
+/// The hardest chart: the highest difficulty slot, then the highest meter, with
+/// unknown labels and meters ranking lowest. Ties go to the earlier chart.
+fn hardest_chart(charts: Vec<ChartBlock<'_>>) -> Option<ChartBlock<'_>> {
+    let rank = |chart: &ChartBlock| {
+        let meter = chart.field(3).parse::<f64>().unwrap_or(f64::NEG_INFINITY);
+        (Difficulty::try_from(chart.field(2)).ok(), meter)
+    };
+    charts
+        .into_iter()
+        .filter(|chart| chart.fields.len() >= 5)
+        .rev()
+        .max_by(|a, b| {
+            let ((a_difficulty, a_meter), (b_difficulty, b_meter)) = (rank(a), rank(b));
+            a_difficulty.cmp(&b_difficulty).then(a_meter.total_cmp(&b_meter))
+        })
+}
+
+/// Finds the chart whose step type and difficulty match the given ones,
+/// compared trimmed and case-insensitively, with difficulties normalized so
+/// `Challenge` finds an `Expert` chart.
+fn find_chart<'a>(data: &'a [u8], step_type: &str, difficulty: &str) -> Option<ChartBlock<'a>> {
+    let text = |field: Option<&&'a [u8]>| field.and_then(|f| std::str::from_utf8(f).ok());
+    let difficulty = normalize_difficulty_label(difficulty);
+    simfile_charts(data)
+        .into_iter()
+        .find(|chart| {
+            let fields = &chart.fields;
+            text(fields.first()).is_some_and(|f| f.trim().eq_ignore_ascii_case(step_type.trim()))
+                && text(fields.get(2)).is_some_and(|f| normalize_difficulty_label(f).eq_ignore_ascii_case(&difficulty))
+        })
+}
+
+/// Input without any tags is a bare notes block (e.g. pasted into a
+/// playground) rather than a simfile.
+fn is_bare_notes_block(data: &[u8], sections: &SimfileSections) -> bool {
+    sections.notes.is_none() && !has_any_tag(data)
+}
+
+/// Picks the chart to analyze: the one matching `chart_key` (step type,
+/// difficulty) if given, else the first one. A bare notes block is analyzed
+/// whole with default header fields; a simfile that merely lacks `#NOTES` is
+/// an error.
+fn select_chart<'a>(
+    data: &'a [u8],
+    sections: &SimfileSections<'a>,
+    chart_key: Option<&(String, String)>,
+) -> io::Result<ChartBlock<'a>> {
+    let incomplete = || io::Error::new(io::ErrorKind::InvalidData, "#NOTES section is incomplete.");
+    let chart = match chart_key {
+        Some((step_type, difficulty)) => find_chart(data, step_type, difficulty).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No chart matches --chart {}:{}", step_type, difficulty),
+            )
+        })?,
+        None if is_bare_notes_block(data, sections) => return Ok(ChartBlock::from_notes(data)),
+        None => match (sections.notedata.clone(), sections.notes) {
+            (Some(chart), _) => chart,
+            (None, Some(notes)) => ChartBlock::from_notes(notes),
+            (None, None) => return Err(incomplete()),
+        },
+    };
+    if chart.fields.len() < 5 {
+        return Err(incomplete());
+    }
+    Ok(chart)
+}
+
+// --------------------------------------------------------------------
+// DWI translation
+// --------------------------------------------------------------------
+
+/// Rows per beat used when laying out DWI notes (192nds of a 4/4 measure).
+const DWI_ROWS_PER_BEAT: usize = 48;
+
+/// Maps a DWI step character to a left/down/up/right bitmask.
+fn dwi_arrow_mask(ch: u8) -> Option<u8> {
+    Some(match ch {
+        b'0' | b'5' => 0b0000,
+        b'1' => 0b0011,
+        b'2' => 0b0010,
+        b'3' => 0b1010,
+        b'4' => 0b0001,
+        b'6' => 0b1000,
+        b'7' => 0b0101,
+        b'8' => 0b0100,
+        b'9' => 0b1100,
+        b'A' => 0b0110,
+        b'B' => 0b1001,
+        _ => return None,
+    })
+}
+
+/// Parses the step string of one DWI chart into rows of `.sm` note characters,
+/// keyed by row index at `DWI_ROWS_PER_BEAT`.
+///
+/// Each character is an 8th note unless wrapped in `(` `)` (16ths), `[` `]`
+/// (24ths), `{` `}` (64ths) or `` ` `` `'` (192nds); `<...>` plays several
+/// characters on one row, and `X!Y` makes the arrows of `Y` hold heads. A hold
+/// ends at the next step on its lane.
+fn parse_dwi_steps(steps: &[u8]) -> BTreeMap<usize, [u8; 4]> {
+    let mut rows = BTreeMap::new();
+    let mut holding = [false; 4];
+    let mut row = 0;
+    let mut row_step = DWI_ROWS_PER_BEAT / 2;
+    let mut i = 0;
+
+    // Reads one step character and an optional `!` hold suffix.
+    let read_step = |i: &mut usize| -> Option<(u8, u8)> {
+        let mask = dwi_arrow_mask(steps[*i])?;
+        *i += 1;
+        let mut hold_mask = 0;
+        if steps.get(*i) == Some(&b'!') {
+            hold_mask = steps.get(*i + 1).and_then(|&c| dwi_arrow_mask(c)).unwrap_or(0);
+            *i += 2;
+        }
+        Some((mask | hold_mask, hold_mask))
+    };
+
+    while i < steps.len() {
+        let (mask, hold_mask) = match steps[i] {
+            b'(' | b'[' | b'{' | b'`' => {
+                row_step = match steps[i] {
+                    b'(' => DWI_ROWS_PER_BEAT / 4,
+                    b'[' => DWI_ROWS_PER_BEAT / 6,
+                    b'{' => DWI_ROWS_PER_BEAT / 16,
+                    _ => 1,
+                };
+                i += 1;
+                continue;
+            }
+            b')' | b']' | b'}' | b'\'' => {
+                row_step = DWI_ROWS_PER_BEAT / 2;
+                i += 1;
+                continue;
+            }
+            b'<' => {
+                i += 1;
+                let (mut mask, mut hold_mask) = (0, 0);
+                while i < steps.len() && steps[i] != b'>' {
+                    match read_step(&mut i) {
+                        Some((m, h)) => {
+                            mask |= m;
+                            hold_mask |= h;
+                        }
+                        None => i += 1,
+                    }
+                }
+                i += 1;
+                (mask, hold_mask)
+            }
+            _ => match read_step(&mut i) {
+                Some(step) => step,
+                None => {
+                    // whitespace and anything unrecognized
+                    i += 1;
+                    continue;
+                }
+            },
+        };
+
+        if mask != 0 {
+            let mut line = [b'0'; 4];
+            for (lane, ch) in line.iter_mut().enumerate() {
+                if mask & (1 << lane) == 0 {
+                    continue;
+                }
+                *ch = if holding[lane] {
+                    holding[lane] = false;
+                    b'3'
+                } else if hold_mask & (1 << lane) != 0 {
+                    holding[lane] = true;
+                    b'2'
+                } else {
+                    b'1'
+                };
+            }
+            rows.insert(row, line);
+        }
+        row += row_step;
+    }
+    rows
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Translates a `.dwi` file into an equivalent `.sm` so the rest of the
+/// pipeline can analyze it unchanged. Only `#SINGLE` charts are kept;
+/// `#FREEZE` becomes `#STOPS`.
+fn dwi_to_sm(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 4);
+    let mut bpm = "";
+    let mut bpm_changes = Vec::new();
+    let mut freezes = Vec::new();
+    let mut charts = Vec::new();
+
+    let mut i = 0;
+    while i < data.len() {
+        if !is_tag_start(&data[i..]) {
+            i += 1;
+            continue;
+        }
+        let colon = i + data[i..].iter().position(|&b| b == b':').unwrap_or(0);
+        let (end, _) = find_tag_end(data, colon + 1);
+        let name = &data[i + 1..colon];
+        let value = &data[colon + 1..end];
+        let text = std::str::from_utf8(value).unwrap_or("").trim();
+        match name {
+            b"TITLE" | b"ARTIST" => {
+                out.push(b'#');
+                out.extend_from_slice(name);
+                out.push(b':');
+                out.extend_from_slice(value);
+                out.extend_from_slice(b";\n");
+            }
+            b"BPM" => bpm = text,
+            b"GAP" => {
+                let gap_ms = text.parse::<f64>().unwrap_or(0.0);
+                let _ = writeln!(out, "#OFFSET:{:.3};", -gap_ms / 1000.0);
+            }
+            b"CHANGEBPM" => {
+                // Change positions are counted in 16th notes.
+                for change in text.split(',') {
+                    if let Some((pos, value)) = change.split_once('=') {
+                        if let Ok(pos) = pos.trim().parse::<f64>() {
+                            bpm_changes.push((pos / 4.0, value.trim()));
+                        }
+                    }
+                }
+            }
+            b"FREEZE" => {
+                // Same 16th-note positions as CHANGEBPM; lengths are in ms.
+                for freeze in text.split(',') {
+                    if let Some((pos, length)) = freeze.split_once('=') {
+                        if let (Ok(pos), Ok(length)) =
+                            (pos.trim().parse::<f64>(), length.trim().parse::<f64>())
+                        {
+                            freezes.push((pos / 4.0, length / 1000.0));
+                        }
+                    }
+                }
+            }
+            b"SINGLE" => {
+                let mut fields = value.splitn(3, |&b| b == b':');
+                if let (Some(difficulty), Some(rating), Some(steps)) =
+                    (fields.next(), fields.next(), fields.next())
+                {
+                    charts.push((difficulty, rating, steps));
+                }
+            }
+            _ => {}
+        }
+        i = end + 1;
+    }
+
+    let _ = write!(out, "#BPMS:0.000={}", bpm);
+    for (beat, value) in bpm_changes {
+        let _ = write!(out, ",{:.3}={}", beat, value);
+    }
+    out.extend_from_slice(b";\n");
+    if !freezes.is_empty() {
+        let stops: Vec<String> = freezes
+            .iter()
+            .map(|(beat, seconds)| format!("{:.3}={:.3}", beat, seconds))
+            .collect();
+        let _ = writeln!(out, "#STOPS:{};", stops.join(","));
+    }
+
+    for (difficulty, rating, steps) in charts {
+        let difficulty = match std::str::from_utf8(difficulty).unwrap_or("").trim() {
+            "BEGINNER" => "Beginner",
+            "BASIC" => "Easy",
+            "ANOTHER" => "Medium",
+            "MANIAC" => "Hard",
+            "SMANIAC" => "Challenge",
+            _ => "Edit",
+        };
+        let rating = std::str::from_utf8(rating).unwrap_or("").trim();
+        let _ = write!(out, "#NOTES:\n     dance-single:\n     :\n     {}:\n     {}:\n     :\n", difficulty, rating);
+
+        let rows = parse_dwi_steps(steps);
+        let rows_per_measure = DWI_ROWS_PER_BEAT * 4;
+        let measures = rows.keys().next_back().map_or(1, |&last| last / rows_per_measure + 1);
+        for measure in 0..measures {
+            if measure > 0 {
+                out.extend_from_slice(b",\n");
+            }
+            // Write each measure at the coarsest spacing that fits its notes,
+            // since minimization only ever halves the row count.
+            let start = measure * rows_per_measure;
+            let measure_rows = rows.range(start..start + rows_per_measure);
+            let spacing = measure_rows.fold(rows_per_measure, |g, (&row, _)| gcd(g, row - start));
+            for row in (start..start + rows_per_measure).step_by(spacing) {
+                out.extend_from_slice(rows.get(&row).unwrap_or(b"0000"));
+                out.push(b'\n');
+            }
+        }
+        out.extend_from_slice(b";\n");
+    }
+    out
 }
 
 // --------------------------------------------------------------------
@@ -740,15 +2146,239 @@ fn median(arr: &[f64]) -> f64 {
         return 0.0;
     }
     let mut sorted = arr.to_vec();
-    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted.sort_by(|a, b| a.total_cmp(b));
     let len = sorted.len();
-    if len % 2 == 0 {
+    if len.is_multiple_of(2) {
         (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
     } else {
         sorted[len / 2]
     }
 }
 
+// --------------------------------------------------------------------
+// Note rows in time
+// --------------------------------------------------------------------
+
+/// One row of the minimized chart, placed at its beat.
+struct NoteRow<'a> {
+    beat: f64,
+    line: &'a [u8],
+}
+
+/// Splits a minimized chart into rows and places each one evenly within its
+/// measure's beat span.
+fn collect_note_rows<'a>(minimized_chart: &'a [u8], measure_beats: &[(f64, f64)]) -> Vec<NoteRow<'a>> {
+    let mut rows = Vec::new();
+    let measures = minimized_chart
+        .split(|&b| b == b'\n')
+        .collect::<Vec<_>>();
+    for (measure, lines) in measures
+        .split(|line| line.first() == Some(&b','))
+        .enumerate()
+    {
+        let lines: Vec<&[u8]> = lines.iter().copied().filter(|l| l.len() >= 4).collect();
+        let (start, beats) = measure_beats
+            .get(measure)
+            .copied()
+            .unwrap_or((measure as f64 * 4.0, 4.0));
+        let rows_in_measure = lines.len();
+        for (index, line) in lines.into_iter().enumerate() {
+            rows.push(NoteRow {
+                beat: start + beats * index as f64 / rows_in_measure as f64,
+                line,
+            });
+        }
+    }
+    rows
+}
+
+/// Rows per beat in StepMania's note grid.
+const ROWS_PER_BEAT: i32 = 48;
+
+/// The note row a beat falls on, snapped to the nearest row. Rows placed by
+/// dividing a measure can land a hair off the grid, so rounding (rather than
+/// truncating) keeps e.g. beat 2.9999999 on row 144. Rows are signed like
+/// StepMania's, so beats before the chart start give negative rows.
+fn beat_to_note_row(beat: f64) -> i32 {
+    (beat * ROWS_PER_BEAT as f64).round() as i32
+}
+
+/// The beat a note row starts at.
+fn note_row_to_beat(row: i32) -> f64 {
+    row as f64 / ROWS_PER_BEAT as f64
+}
+
+/// Note quantizations in the order of their color index: 4th (red), 8th
+/// (blue), 12th (purple), 16th (yellow), 24th (pink), 32nd (orange), 48th
+/// (cyan), 64th (green), 192nd (gray) in StepMania's default coloring.
+const QUANTIZATIONS: [i32; 9] = [4, 8, 12, 16, 24, 32, 48, 64, 192];
+
+/// Returns (row, color index into `QUANTIZATIONS`) for every row holding
+/// anything, where row counts `ROWS_PER_BEAT` per beat from the chart start.
+fn compute_quantization_colors(rows: &[NoteRow]) -> Vec<(i32, usize)> {
+    rows.iter()
+        .filter(|row| row.line.iter().any(|&c| c != b'0'))
+        .map(|row| {
+            let grid_row = beat_to_note_row(row.beat);
+            let color = QUANTIZATIONS
+                .iter()
+                .position(|&q| grid_row % (ROWS_PER_BEAT * 4 / q) == 0)
+                .unwrap_or(QUANTIZATIONS.len() - 1);
+            (grid_row, color)
+        })
+        .collect()
+}
+
+/// Single-note rows needed on each side of a jump before it looks like a
+/// stray extra arrow.
+const SUSPICIOUS_JUMP_CONTEXT: usize = 8;
+
+/// Jumps sitting alone in single-note stream, often an arrow added by
+/// mistake. Returns their rows, counted like `compute_quantization_colors`.
+/// Hands (3+ arrows) are left alone, as they are hard to place by accident.
+fn find_suspicious_jumps(rows: &[NoteRow]) -> Vec<i32> {
+    let notes: Vec<(f64, u32)> = rows
+        .iter()
+        .map(|row| (row.beat, line_to_bitmask(row.line).count_ones()))
+        .filter(|&(_, arrows)| arrows > 0)
+        .collect();
+    if notes.len() < 2 * SUSPICIOUS_JUMP_CONTEXT + 1 {
+        return Vec::new();
+    }
+    notes
+        .windows(2 * SUSPICIOUS_JUMP_CONTEXT + 1)
+        .filter(|window| {
+            let (before, rest) = window.split_at(SUSPICIOUS_JUMP_CONTEXT);
+            rest[0].1 == 2 && before.iter().chain(&rest[1..]).all(|&(_, arrows)| arrows == 1)
+        })
+        .map(|window| beat_to_note_row(window[SUSPICIOUS_JUMP_CONTEXT].0))
+        .collect()
+}
+
+/// Average BPM in effect at each note, i.e. the tempo the notes are actually
+/// played at rather than the tempo of the song overall. A jump counts as two
+/// notes.
+fn compute_notes_weighted_bpm(rows: &[NoteRow], bpm_map: &[(f64, f64)]) -> f64 {
+    let (sum, count) = rows
+        .iter()
+        .map(|row| (get_current_bpm(row.beat, bpm_map), line_to_bitmask(row.line).count_ones()))
+        .filter(|&(bpm, notes)| notes > 0 && !is_warp_bpm(bpm))
+        .fold((0.0, 0u32), |(sum, count), (bpm, notes)| (sum + bpm * notes as f64, count + notes));
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+/// Where a chart's notes sit in the song audio, for estimating padding around
+/// the playable part. Seconds are song time, i.e. already shifted by `#OFFSET`.
+struct ChartTiming {
+    offset: f64,
+    sample_start: f64,
+    sample_length: f64,
+    first_note_second: f64,
+    last_note_second: f64,
+}
+
+fn compute_chart_timing(
+    rows: &[NoteRow],
+    timing: &TimingData,
+    offset: f64,
+    sample_start: f64,
+    sample_length: f64,
+) -> ChartTiming {
+    let mut note_beats = rows
+        .iter()
+        .filter(|row| line_to_bitmask(row.line) != 0)
+        .map(|row| row.beat);
+    let first_beat = note_beats.next();
+    let last_beat = note_beats.next_back().or(first_beat);
+    let song_second = |beat: Option<f64>| beat.map_or(0.0, |b| timing.second_at_beat(b) - offset);
+
+    ChartTiming {
+        offset,
+        sample_start,
+        sample_length,
+        first_note_second: song_second(first_beat),
+        last_note_second: song_second(last_beat),
+    }
+}
+
+/// Where the streaming part of a chart starts and stops: the first and last
+/// measure dense enough for the lowest run level, and the song time at the
+/// start of the first and the end of the last.
+struct StreamSpan {
+    onset_measure: usize,
+    onset_second: f64,
+    end_measure: usize,
+    end_second: f64,
+}
+
+fn compute_stream_span(
+    measure_densities: &[usize],
+    measure_beats: &[(f64, f64)],
+    thresholds: &StreamThresholds,
+    timing: &TimingData,
+    offset: f64,
+    first_measure: usize,
+) -> Option<StreamSpan> {
+    let is_stream = |&d: &usize| categorize_measure_density(d, thresholds) != RunDensity::Break;
+    let first = measure_densities.iter().position(is_stream)?;
+    let last = measure_densities.iter().rposition(is_stream)?;
+    let (first_start, _) = *measure_beats.get(first)?;
+    let (last_start, last_beats) = *measure_beats.get(last)?;
+    Some(StreamSpan {
+        onset_measure: first_measure + first,
+        onset_second: timing.second_at_beat(first_start) - offset,
+        end_measure: first_measure + last,
+        end_second: timing.second_at_beat(last_start + last_beats) - offset,
+    })
+}
+
+/// Counts the arrows on rows inside `#FAKES` regions ((start beat, length) pairs);
+/// the engine never judges these, so they inflate the displayed step count.
+fn count_faked_notes(rows: &[NoteRow], fake_regions: &[(f64, f64)]) -> u32 {
+    rows.iter()
+        .filter(|row| {
+            fake_regions
+                .iter()
+                .any(|&(start, len)| row.beat >= start && row.beat < start + len)
+        })
+        .map(|row| line_to_bitmask(row.line).count_ones())
+        .sum()
+}
+
+/// Counts mines whose lane gets a tap, hold or roll head on the very next row
+/// that has anything on it. These force the foot off and back onto the panel,
+/// unlike decorative mines placed away from the notes.
+fn count_guide_mines(rows: &[NoteRow]) -> u32 {
+    let occupied: Vec<&[u8]> = rows
+        .iter()
+        .map(|row| row.line)
+        .filter(|line| line.iter().any(|&c| c != b'0'))
+        .collect();
+    occupied
+        .windows(2)
+        .map(|pair| {
+            pair[0]
+                .iter()
+                .zip(pair[1])
+                .filter(|&(&mine, &next)| mine == b'M' && matches!(next, b'1' | b'2' | b'4'))
+                .count() as u32
+        })
+        .sum()
+}
+
+/// Fills in the arrow stats that need the timing: faked notes and guide mines.
+/// Like the rest of `ArrowStats` they cover the whole chart, whatever
+/// `--counts-only` or `--measures` later leave out.
+fn count_timed_arrow_stats(stats: &mut ArrowStats, minimized_chart: &[u8], timing: &TimingData, measure_count: usize) {
+    let rows = collect_note_rows(minimized_chart, &timing.measure_beats(measure_count));
+    stats.faked_notes = count_faked_notes(&rows, &timing.fakes);
+    stats.guide_mines = count_guide_mines(&rows);
+}
+
 // --------------------------------------------------------------------
 // Pattern Analysis
 // --------------------------------------------------------------------
@@ -786,16 +2416,18 @@ fn parse_bitmask_chart(chart_data: &[u8]) -> Vec<u8> {
     bitmasks
 }
 
-fn count_candles(bitmasks: &[u8]) -> (u32, u32) {
-    const LEFT_FOOT_PATTERNS: &[[u8; 3]] = &[
-        [0b0010, 0b1000, 0b0100],
-        [0b0100, 0b1000, 0b0010],
-    ];
-    const RIGHT_FOOT_PATTERNS: &[[u8; 3]] = &[
-        [0b0010, 0b0001, 0b0100],
-        [0b0100, 0b0001, 0b0010],
-    ];
+/// Down-left-up / up-left-down sweeps taken with the left foot.
+const LEFT_FOOT_CANDLES: &[[u8; 3]] = &[
+    [0b0010, 0b1000, 0b0100],
+    [0b0100, 0b1000, 0b0010],
+];
+/// Down-right-up / up-right-down sweeps taken with the right foot.
+const RIGHT_FOOT_CANDLES: &[[u8; 3]] = &[
+    [0b0010, 0b0001, 0b0100],
+    [0b0100, 0b0001, 0b0010],
+];
 
+fn count_candles(bitmasks: &[u8]) -> (u32, u32) {
     let mut left_foot = 0u32;
     let mut right_foot = 0u32;
 
@@ -805,16 +2437,37 @@ fn count_candles(bitmasks: &[u8]) -> (u32, u32) {
 
     for i in 0..(bitmasks.len() - 2) {
         let block = [bitmasks[i], bitmasks[i + 1], bitmasks[i + 2]];
-        if LEFT_FOOT_PATTERNS.iter().any(|pat| *pat == block) {
+        if LEFT_FOOT_CANDLES.contains(&block) {
             left_foot += 1;
         }
-        if RIGHT_FOOT_PATTERNS.iter().any(|pat| *pat == block) {
+        if RIGHT_FOOT_CANDLES.contains(&block) {
             right_foot += 1;
         }
     }
     (left_foot, right_foot)
 }
 
+/// Groups candles into runs: a candle starting within two rows of the previous
+/// one continues the run. Returns (start row, number of candles) per run, rows
+/// being indices into the minimized chart.
+fn collect_candle_runs(bitmasks: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut last_start = None;
+    for (i, block) in bitmasks.windows(3).enumerate() {
+        if !LEFT_FOOT_CANDLES.contains(&[block[0], block[1], block[2]])
+            && !RIGHT_FOOT_CANDLES.contains(&[block[0], block[1], block[2]])
+        {
+            continue;
+        }
+        match (last_start, runs.last_mut()) {
+            (Some(prev), Some(run)) if i - prev <= 2 => run.1 += 1,
+            _ => runs.push((i, 1)),
+        }
+        last_start = Some(i);
+    }
+    runs
+}
+
 fn count_monos(bitmasks: &[u8]) -> (u32, u32) {
     const VALID_LD_RU: &[[u8; 4]] = &[
         [0b0001, 0b0100, 0b0010, 0b1000],
@@ -902,22 +2555,22 @@ fn count_boxes(bitmasks: &[u8]) -> (u32, u32, u32, u32, u32, u32) {
 
     for i in 0..(bitmasks.len() - 3) {
         let block = [bitmasks[i], bitmasks[i + 1], bitmasks[i + 2], bitmasks[i + 3]];
-        if LR_BOXES.iter().any(|pat| *pat == block) {
+        if LR_BOXES.contains(&block) {
             lr += 1;
         }
-        if UD_BOXES.iter().any(|pat| *pat == block) {
+        if UD_BOXES.contains(&block) {
             ud += 1;
         }
-        if CORNER_LD_BOXES.iter().any(|pat| *pat == block) {
+        if CORNER_LD_BOXES.contains(&block) {
             corner_ld += 1;
         }
-        if CORNER_LU_BOXES.iter().any(|pat| *pat == block) {
+        if CORNER_LU_BOXES.contains(&block) {
             corner_lu += 1;
         }
-        if CORNER_RD_BOXES.iter().any(|pat| *pat == block) {
+        if CORNER_RD_BOXES.contains(&block) {
             corner_rd += 1;
         }
-        if CORNER_RU_BOXES.iter().any(|pat| *pat == block) {
+        if CORNER_RU_BOXES.contains(&block) {
             corner_ru += 1;
         }
     }
@@ -952,19 +2605,19 @@ fn count_doritos(bitmasks: &[u8]) -> (u32, u32, u32, u32) {
     while i + 4 < bitmasks.len() {
         if bitmasks[i..i+5].iter().all(|&b| b.count_ones() == 1) {
             let block = &bitmasks[i..i+5];
-            if block == &RIGHT_DORITO {
+            if block == RIGHT_DORITO {
                 rd_count += 1;
                 i += 5;
                 continue;
-            } else if block == &LEFT_DORITO {
+            } else if block == LEFT_DORITO {
                 ld_count += 1;
                 i += 5;
                 continue;
-            } else if block == &INV_RIGHT_DORITO {
+            } else if block == INV_RIGHT_DORITO {
                 ird_count += 1;
                 i += 5;
                 continue;
-            } else if block == &INV_LEFT_DORITO {
+            } else if block == INV_LEFT_DORITO {
                 ild_count += 1;
                 i += 5;
                 continue;
@@ -976,6 +2629,43 @@ fn count_doritos(bitmasks: &[u8]) -> (u32, u32, u32, u32) {
     (rd_count, ld_count, ird_count, ild_count)
 }
 
+/// Finds drills: single arrows alternating between the same two panels
+/// (L-R-L-R, U-D-U-D, ...), ignoring empty rows. Returns (drills of at least
+/// `min_notes` notes, longest drill in notes).
+fn count_drills(bitmasks: &[u8], min_notes: usize) -> (u32, u32) {
+    let notes: Vec<u8> = bitmasks.iter().copied().filter(|&b| b != 0).collect();
+    let is_single = |b: u8| b.count_ones() == 1;
+
+    let mut drills = 0u32;
+    let mut longest = 0usize;
+    let mut run_len = 0usize;
+    let mut close_run = |len: usize| {
+        if len >= min_notes.max(2) {
+            drills += 1;
+            longest = longest.max(len);
+        }
+    };
+
+    for i in 0..notes.len() {
+        let curr = notes[i];
+        if !is_single(curr) {
+            close_run(run_len);
+            run_len = 0;
+            continue;
+        }
+        let alternates = i > 0 && is_single(notes[i - 1]) && notes[i - 1] != curr;
+        if alternates && (run_len < 2 || notes[i - 2] == curr) {
+            run_len += 1;
+        } else {
+            close_run(run_len);
+            run_len = if alternates { 2 } else { 1 };
+        }
+    }
+    close_run(run_len);
+
+    (drills, longest as u32)
+}
+
 fn count_anchors(bitmasks: &[u8], arrow_bit: u8) -> u32 {
     let mut count = 0;
     let n = bitmasks.len();
@@ -996,17 +2686,191 @@ fn count_anchors(bitmasks: &[u8], arrow_bit: u8) -> u32 {
     count
 }
 
-fn do_pattern_analysis(bitmasks: &[u8], total_arrows: u32) -> PatternStats {
+// --------------------------------------------------------------------
+// Custom patterns
+// --------------------------------------------------------------------
+
+/// One element of a custom pattern, matched against the chart's steps
+/// (non-empty rows), so how finely a measure is written doesn't matter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatternAtom {
+    /// `L`, `D`, `U` or `R`: that single arrow.
+    Arrow(u8),
+    /// `*`: any single arrow.
+    AnySingle,
+    /// `.`: any one step, jumps and hands included, or no step at all.
+    Any,
+}
+
+/// Largest `n` accepted in a custom pattern's `{n}`.
+const MAX_PATTERN_REPEAT: usize = 256;
+
+/// Parses a custom pattern such as `L*R{3}`: arrows `LDUR`, `*` for any single
+/// arrow, `.` for any step or none, and `{n}` to repeat the previous element n
+/// times.
+fn parse_custom_pattern(pattern: &str) -> Result<Vec<PatternAtom>, String> {
+    let mut atoms = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let atom = match c.to_ascii_uppercase() {
+            'L' => PatternAtom::Arrow(0b0001),
+            'D' => PatternAtom::Arrow(0b0010),
+            'U' => PatternAtom::Arrow(0b0100),
+            'R' => PatternAtom::Arrow(0b1000),
+            '*' => PatternAtom::AnySingle,
+            '.' => PatternAtom::Any,
+            _ => return Err(format!("Invalid character '{}' in pattern {}", c, pattern)),
+        };
+        atoms.push(atom);
+
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut digits = String::new();
+            let mut closed = false;
+            for c in chars.by_ref() {
+                if c == '}' {
+                    closed = true;
+                    break;
+                }
+                digits.push(c);
+            }
+            if !closed {
+                return Err(format!("Missing '}}' in pattern {}", pattern));
+            }
+            let count = digits
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| (1..=MAX_PATTERN_REPEAT).contains(&n))
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid repetition {{{}}} in pattern {} (expected 1 to {})",
+                        digits, pattern, MAX_PATTERN_REPEAT
+                    )
+                })?;
+            atoms.extend(std::iter::repeat_n(atom, count - 1));
+        }
+    }
+    if atoms.is_empty() {
+        return Err("Empty custom pattern".to_string());
+    }
+    Ok(atoms)
+}
+
+/// Counts the steps where a custom pattern starts, overlapping occurrences
+/// included. Empty rows are skipped.
+fn count_custom_pattern(bitmasks: &[u8], atoms: &[PatternAtom]) -> u32 {
+    let steps: Vec<u8> = bitmasks.iter().copied().filter(|&b| b != 0).collect();
+    // Working back from the last step, `next[k]` says whether `atoms[k..]`
+    // matches from the following step on and `here[k]` from this one.
+    let mut next = vec![false; atoms.len() + 1];
+    next[atoms.len()] = true;
+    // Past the last step only optional `.`s can still match.
+    for k in (0..atoms.len()).rev() {
+        next[k] = atoms[k] == PatternAtom::Any && next[k + 1];
+    }
+    let mut count = 0;
+    for &step in steps.iter().rev() {
+        let mut here = vec![false; atoms.len() + 1];
+        here[atoms.len()] = true;
+        for k in (0..atoms.len()).rev() {
+            here[k] = match atoms[k] {
+                PatternAtom::Arrow(mask) => step == mask && next[k + 1],
+                PatternAtom::AnySingle => step.count_ones() == 1 && next[k + 1],
+                PatternAtom::Any => here[k + 1] || next[k + 1],
+            };
+        }
+        if here[0] {
+            count += 1;
+        }
+        next = here;
+    }
+    count
+}
+
+impl PatternStats {
+    /// The plain occurrence counts, by output name (percentages and lengths left out).
+    fn counts(&self) -> [(&'static str, u32); 20] {
+        [
+            ("left_foot_candles", self.left_foot_candles),
+            ("right_foot_candles", self.right_foot_candles),
+            ("total_candles", self.total_candles),
+            ("ld_ru_mono", self.ld_ru_mono),
+            ("lu_rd_mono", self.lu_rd_mono),
+            ("lr_boxes", self.lr_boxes),
+            ("ud_boxes", self.ud_boxes),
+            ("corner_ld_boxes", self.corner_ld_boxes),
+            ("corner_lu_boxes", self.corner_lu_boxes),
+            ("corner_rd_boxes", self.corner_rd_boxes),
+            ("corner_ru_boxes", self.corner_ru_boxes),
+            ("anchor_left", self.anchor_left),
+            ("anchor_down", self.anchor_down),
+            ("anchor_up", self.anchor_up),
+            ("anchor_right", self.anchor_right),
+            ("right_dorito", self.right_dorito),
+            ("left_dorito", self.left_dorito),
+            ("inv_right_dorito", self.inv_right_dorito),
+            ("inv_left_dorito", self.inv_left_dorito),
+            ("drills", self.drills),
+        ]
+    }
+
+    /// Each count per 1000 steps, so charts of different lengths compare by
+    /// pattern density. All zero for a chart without steps.
+    fn per_1k_notes(&self, total_steps: u32) -> Vec<(&'static str, f64)> {
+        self.counts()
+            .into_iter()
+            .map(|(name, count)| {
+                let per_1k = if total_steps > 0 {
+                    count as f64 * 1000.0 / total_steps as f64
+                } else {
+                    0.0
+                };
+                (name, per_1k)
+            })
+            .collect()
+    }
+}
+
+/// What `candles_percent` is measured against.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CandleDenominator {
+    /// The most candles a chart of this many arrows could hold,
+    /// `(total_arrows - 1) / 2`. Reads low on stream-heavy files.
+    TheoreticalMax,
+    /// The single up and down notes, i.e. the notes a candle can land on.
+    UpDownNotes,
+}
+
+impl FromStr for CandleDenominator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "max" => Ok(CandleDenominator::TheoreticalMax),
+            "updown" => Ok(CandleDenominator::UpDownNotes),
+            _ => Err(format!("Unknown candle denominator: {}", s)),
+        }
+    }
+}
+
+fn do_pattern_analysis(
+    bitmasks: &[u8],
+    total_arrows: u32,
+    min_drill: usize,
+    candle_denominator: CandleDenominator,
+) -> PatternStats {
     let (left_foot_candles, right_foot_candles) = count_candles(bitmasks);
     let total_candles = left_foot_candles + right_foot_candles;
 
-    let candles_percent = if total_arrows > 1 {
-        let denom = ((total_arrows.saturating_sub(1) / 2) as f64).floor();
-        if denom > 0.0 {
-            (total_candles as f64 / denom) * 100.0
-        } else {
-            0.0
-        }
+    let denom = match candle_denominator {
+        CandleDenominator::TheoreticalMax => (total_arrows.saturating_sub(1) / 2) as f64,
+        CandleDenominator::UpDownNotes => bitmasks
+            .iter()
+            .filter(|&&mask| mask == 0b0010 || mask == 0b0100)
+            .count() as f64,
+    };
+    let candles_percent = if denom > 0.0 {
+        (total_candles as f64 / denom) * 100.0
     } else {
         0.0
     };
@@ -1029,6 +2893,8 @@ fn do_pattern_analysis(bitmasks: &[u8], total_arrows: u32) -> PatternStats {
 
     let (rd, ld, ird, ild) = count_doritos(bitmasks);
 
+    let (drills, longest_drill) = count_drills(bitmasks, min_drill);
+
     PatternStats {
         left_foot_candles,
         right_foot_candles,
@@ -1051,6 +2917,8 @@ fn do_pattern_analysis(bitmasks: &[u8], total_arrows: u32) -> PatternStats {
         left_dorito: ld,
         inv_right_dorito: ird,
         inv_left_dorito: ild,
+        drills,
+        longest_drill,
     }
 }
 
@@ -1139,79 +3007,504 @@ fn generate_density_graph_png(
 // Main
 // --------------------------------------------------------------------
 
-fn main() -> io::Result<()> {
-    // Start timer BEFORE any processing:
-    let start_time = Instant::now();
+/// How the analysis is printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputMode {
+    Text,
+    Json,
+    JsonPretty,
+    Csv,
+    /// One aligned line per file, for scanning packs with grep/sort.
+    Summary,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(OutputMode::Text),
+            "json" => Ok(OutputMode::Json),
+            "json-pretty" => Ok(OutputMode::JsonPretty),
+            "csv" => Ok(OutputMode::Csv),
+            "summary" => Ok(OutputMode::Summary),
+            _ => Err(format!("Unknown output mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputMode::Text => "text",
+            OutputMode::Json => "json",
+            OutputMode::JsonPretty => "json-pretty",
+            OutputMode::Csv => "csv",
+            OutputMode::Summary => "summary",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Command-line options shared by every analysis run.
+struct Options {
+    generate_png: bool,
+    output_mode: OutputMode,
+    strip_tags: bool,
+    counts_only: bool,
+    /// Decimal places for computed floats in JSON output.
+    precision: usize,
+    nps_at: Option<f64>,
+    bpms: Option<String>,
+    collect_candle_runs: bool,
+    /// Report note counts per lane per measure.
+    playfield_heatmap: bool,
+    /// Report NPS, stream/break and jacks for each measure.
+    measure_profiles: bool,
+    /// Report the quantization color of each note row.
+    quantization: bool,
+    /// User patterns to count, with their parsed form.
+    custom_patterns: Vec<(String, Vec<PatternAtom>)>,
+    /// Minimum notes for an alternation to count as a drill.
+    min_drill: usize,
+    /// Notes per measure needed for each run level.
+    stream_thresholds: StreamThresholds,
+    /// Denominator for `candles_percent`.
+    candle_denominator: CandleDenominator,
+    /// Half-open range of measures to restrict the analysis to.
+    measure_range: Option<(usize, usize)>,
+    /// Analyze the chart with this (step type, difficulty) instead of the first one.
+    chart: Option<(String, String)>,
+    /// Keep repeated BPM segments instead of merging them before hashing.
+    keep_bpm_segments: bool,
+    /// Leave mines out of the chart hash.
+    hash_ignore_mines: bool,
+    /// Embed the minimized note data in JSON output.
+    include_notes: bool,
+    /// Field separator for CSV output.
+    csv_delimiter: char,
+    /// Print only the breakdown in this mode.
+    breakdown_only: Option<BreakdownMode>,
+    /// Print only the minimized note data.
+    print_minimized: bool,
+    /// Print only the per-measure NPS graph data as JSON.
+    graph_json: bool,
+    /// Print diagnostics to stderr.
+    verbose: bool,
+}
 
+impl Options {
+    fn from_args(args: &[String]) -> Options {
+        let precision = match arg_value(args, "--precision") {
+            Some(p) => p.parse::<usize>().unwrap_or_else(|_| {
+                eprintln!("Invalid --precision value: {}", p);
+                std::process::exit(1);
+            }),
+            None => 2,
+        };
+        let nps_at = arg_value(args, "--nps-at").map(|t| {
+            t.parse::<f64>().ok().filter(|t| t.is_finite()).unwrap_or_else(|| {
+                eprintln!("Invalid --nps-at value: {}", t);
+                std::process::exit(1);
+            })
+        });
+        let min_drill = match arg_value(args, "--min-drill") {
+            Some(n) => n.parse::<usize>().unwrap_or_else(|_| {
+                eprintln!("Invalid --min-drill value: {}", n);
+                std::process::exit(1);
+            }),
+            None => 4,
+        };
+        let stream_thresholds = match arg_value(args, "--stream-thresholds") {
+            Some(list) => {
+                let values: Vec<usize> = list
+                    .split(',')
+                    .map(|v| v.trim().parse::<usize>())
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_default();
+                match <StreamThresholds>::try_from(values) {
+                    Ok(t) if t[0] > 0 && t.windows(2).all(|w| w[0] <= w[1]) => t,
+                    _ => {
+                        eprintln!("Invalid --stream-thresholds value (expected 4 ascending counts): {}", list);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => DEFAULT_STREAM_THRESHOLDS,
+        };
+        let candle_denominator = match arg_value(args, "--candle-denominator") {
+            Some(d) => d.parse::<CandleDenominator>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+            None => CandleDenominator::TheoreticalMax,
+        };
+        let measure_range = arg_value(args, "--measures").map(|range| {
+            let parsed = range
+                .split_once(':')
+                .and_then(|(start, end)| Some((start.trim().parse::<usize>().ok()?, end.trim().parse::<usize>().ok()?)));
+            match parsed {
+                Some((start, end)) if start < end => (start, end),
+                _ => {
+                    eprintln!("Invalid --measures value (expected <start>:<end>, end exclusive): {}", range);
+                    std::process::exit(1);
+                }
+            }
+        });
+        let breakdown_only = arg_value(args, "--breakdown").map(|mode| {
+            mode.parse::<BreakdownMode>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        });
+        let csv_delimiter = match arg_value(args, "--delimiter") {
+            Some("tab") | Some("\t") => '\t',
+            Some(d) if d.chars().count() == 1 && !matches!(d, "\"" | "\r" | "\n") => d.chars().next().unwrap_or(','),
+            Some(d) => {
+                eprintln!("Invalid --delimiter value (expected a single character or \"tab\"): {}", d);
+                std::process::exit(1);
+            }
+            None => ',',
+        };
+        let chart = arg_value(args, "--chart").map(|key| match key.split_once(':') {
+            Some((step_type, difficulty)) => (step_type.to_owned(), difficulty.to_owned()),
+            None => {
+                eprintln!("Invalid --chart value (expected <step_type>:<difficulty>): {}", key);
+                std::process::exit(1);
+            }
+        });
+        let custom_patterns = arg_values(args, "--custom-pattern")
+            .into_iter()
+            .map(|pattern| match parse_custom_pattern(pattern) {
+                Ok(atoms) => (pattern.to_string(), atoms),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            })
+            .collect();
+        // `--output <mode>`, or the last `--<mode>` shorthand such as `--json`.
+        let output_mode = match arg_value(args, "--output") {
+            Some(m) => m.parse::<OutputMode>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+            None => args
+                .iter()
+                .rev()
+                .find_map(|a| a.strip_prefix("--").and_then(|m| m.parse().ok()))
+                .unwrap_or(OutputMode::Text),
+        };
+        // Diagnostics on stderr are on by default; the last of -q/-v wins.
+        let verbose = args
+            .iter()
+            .rev()
+            .find_map(|a| match a.as_str() {
+                "-q" | "--quiet" => Some(false),
+                "-v" | "--verbose" => Some(true),
+                _ => None,
+            })
+            .unwrap_or(true);
+
+        Options {
+            generate_png: args.iter().any(|a| a == "--png"),
+            output_mode,
+            strip_tags: args.iter().any(|a| a == "--strip-tags"),
+            counts_only: args.iter().any(|a| a == "--counts-only"),
+            precision,
+            nps_at,
+            bpms: arg_value(args, "--bpms").map(str::to_owned),
+            collect_candle_runs: args.iter().any(|a| a == "--candle-runs"),
+            playfield_heatmap: args.iter().any(|a| a == "--heatmap"),
+            measure_profiles: args.iter().any(|a| a == "--measure-profiles"),
+            quantization: args.iter().any(|a| a == "--quantization"),
+            custom_patterns,
+            min_drill,
+            stream_thresholds,
+            candle_denominator,
+            measure_range,
+            chart,
+            keep_bpm_segments: args.iter().any(|a| a == "--keep-bpm-segments"),
+            hash_ignore_mines: args.iter().any(|a| a == "--hash-ignore-mines"),
+            include_notes: args.iter().any(|a| a == "--include-notes"),
+            csv_delimiter,
+            breakdown_only,
+            print_minimized: args.iter().any(|a| a == "--minimize"),
+            graph_json: args.iter().any(|a| a == "--graph-json"),
+            verbose,
+        }
+    }
+}
+
+/// File extensions `analyze_file` understands.
+const SUPPORTED_EXTENSIONS: [&str; 3] = ["sm", "ssc", "dwi"];
+
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} <simfile_path> [--help] [--version] [--watch <simfile_path>] [--png] [--output text|json|json-pretty|csv|summary] [--json] [--json-pretty] [--csv] [--summary] [--delimiter <char>|tab] [--strip-tags] [--counts-only] [--bpms <bpms>] [--keep-bpm-segments] [--hash-ignore-mines] [--precision <n>] [--nps-at <seconds>] [--candle-runs] [--heatmap] [--measure-profiles] [--quantization] [--min-drill <notes>] [--stream-thresholds <n16,n20,n24,n32>] [--candle-denominator max|updown] [--custom-pattern <pattern>]... [--chart <step_type>:<difficulty>] [--measures <start>:<end>] [--breakdown detailed|partial|simple|annotated] [--minimize] [--include-notes] [--graph-json] [-q|--quiet] [-v|--verbose]",
+        program
+    )
+}
+
+fn main() {
     let args: Vec<String> = args().collect();
+    // These answer before anything is analyzed, even when a path is also given.
+    if args.iter().skip(1).any(|a| a == "--version" || a == "-V") {
+        println!("rssp {}", env!("CARGO_PKG_VERSION"));
+        println!("Supported formats: {} (plain or gzip-compressed)", SUPPORTED_EXTENSIONS.join(", "));
+        return;
+    }
+    if args.iter().skip(1).any(|a| a == "--help" || a == "-h") {
+        println!("{}", usage(&args[0]));
+        return;
+    }
     if args.len() < 2 {
-        eprintln!("Usage: {} <simfile_path> [--png] [--json] [--strip-tags]", args[0]);
+        eprintln!("{}", usage(&args[0]));
+        std::process::exit(1);
+    }
+    let options = Options::from_args(&args);
+
+    let result = match arg_value(&args, "--watch") {
+        Some(path) => watch_file(path, &options),
+        None => analyze_file(&args[1], &options),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
         std::process::exit(1);
     }
+}
+
+/// Re-runs the analysis whenever the simfile changes on disk, debounced so an
+/// editor's burst of writes only triggers one run. Analysis errors are printed
+/// and watching continues.
+fn watch_file(simfile_path: &str, options: &Options) -> io::Result<()> {
+    const DEBOUNCE: Duration = Duration::from_millis(200);
+
+    let path = Path::new(simfile_path);
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    // Watch the parent directory: editors often save by replacing the file,
+    // which would silently end a watch on the file itself.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(io::Error::other)?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(io::Error::other)?;
+
+    let mut last_modified = modified(path);
+    if let Err(e) = analyze_file(simfile_path, options) {
+        eprintln!("{}", e);
+    }
+    for event in &rx {
+        let touches_file = match event {
+            Ok(event) => event.paths.iter().any(|p| p.file_name() == path.file_name()),
+            Err(_) => false,
+        };
+        if !touches_file {
+            continue;
+        }
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+        let now_modified = modified(path);
+        if now_modified.is_none() || now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+        if let Err(e) = analyze_file(simfile_path, options) {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Drops the UTF-8 BOM Windows editors like to prepend.
+fn strip_bom(data: &mut Vec<u8>) {
+    if data.starts_with(b"\xEF\xBB\xBF") {
+        data.drain(..3);
+    }
+}
+
+fn analyze_file(simfile_path: &str, options: &Options) -> io::Result<()> {
+    // Start timer BEFORE any processing:
+    let start_time = Instant::now();
 
-    let simfile_path = &args[1];
     let mut file = File::open(simfile_path)?;
     let mut simfile_data = Vec::new();
     file.read_to_end(&mut simfile_data)?;
+    // Compressed packs store `.sm.gz` and friends; recognize gzip by its magic
+    // bytes and judge the format by the name without the `.gz`.
+    let mut format_path = Path::new(simfile_path);
+    if simfile_data.starts_with(&[0x1f, 0x8b]) {
+        let mut decompressed = Vec::with_capacity(simfile_data.len() * 4);
+        GzDecoder::new(simfile_data.as_slice()).read_to_end(&mut decompressed)?;
+        simfile_data = decompressed;
+        if format_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz")) {
+            format_path = Path::new(format_path.file_stem().unwrap_or_default());
+        }
+    }
+    strip_bom(&mut simfile_data);
+    let is_dwi = format_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("dwi"));
+    if is_dwi {
+        simfile_data = dwi_to_sm(&simfile_data);
+    }
 
-    let generate_png  = args.iter().any(|a| a == "--png");
-    let generate_json = args.iter().any(|a| a == "--json");
-    let strip_tags    = args.iter().any(|a| a == "--strip-tags");
-
-    let (
-        title_opt,
-        subtitle_opt,
-        artist_opt,
-        titletranslit_opt,
-        subtitletranslit_opt,
-        artisttranslit_opt,
-        bpms_opt,
-        notes_opt,
-    ) = extract_sections(&simfile_data)?;
+    let mut sections = extract_sections(&simfile_data)?;
+    if options.verbose {
+        for tag in &sections.auto_terminated {
+            eprintln!("Warning: {} is missing its ';' terminator", tag);
+        }
+    }
 
     // Convert to owned String so we can conditionally strip tags.
-    let mut title_str = std::str::from_utf8(title_opt.unwrap_or(b"<invalid-title>"))
+    let mut title_str = std::str::from_utf8(sections.title.unwrap_or(b"<invalid-title>"))
         .unwrap_or("<invalid-title>")
         .to_owned();
     
     // If --strip-tags is present, remove bracketed numeric tags from the title
-    if strip_tags {
+    if options.strip_tags {
         title_str = strip_title_tags(&title_str);
     }
 
-    let subtitle_str = std::str::from_utf8(subtitle_opt.unwrap_or(b"<invalid-subtitle>"))
+    let subtitle_str = std::str::from_utf8(sections.subtitle.unwrap_or(b"<invalid-subtitle>"))
         .unwrap_or("<invalid-subtitle>");
-    let artist_str = std::str::from_utf8(artist_opt.unwrap_or(b"<invalid-artist>"))
+    let artist_str = std::str::from_utf8(sections.artist.unwrap_or(b"<invalid-artist>"))
         .unwrap_or("<invalid-artist>");
-    let bpms_raw = std::str::from_utf8(bpms_opt.unwrap_or(b"<invalid-bpms>"))
-        .unwrap_or("<invalid-bpms>");
-    let normalized_bpms = normalize_float_digits(bpms_raw);
-
-    let titletranslit_str = std::str::from_utf8(titletranslit_opt.unwrap_or(b""))
+    let titletranslit_str = std::str::from_utf8(sections.titletranslit.unwrap_or(b""))
         .unwrap_or("");
-    let subtitletranslit_str = std::str::from_utf8(subtitletranslit_opt.unwrap_or(b""))
+    let subtitletranslit_str = std::str::from_utf8(sections.subtitletranslit.unwrap_or(b""))
         .unwrap_or("");
-    let artisttranslit_str = std::str::from_utf8(artisttranslit_opt.unwrap_or(b""))
+    let artisttranslit_str = std::str::from_utf8(sections.artisttranslit.unwrap_or(b""))
         .unwrap_or("");
+    let bg_changes_str = String::from_utf8_lossy(sections.bg_changes.unwrap_or(b""));
+    let fg_changes_str = String::from_utf8_lossy(sections.fg_changes.unwrap_or(b""));
+    let has_video_background = has_video_background(&bg_changes_str);
+
+    // A summary line stands for the whole file, so it shows the hardest chart.
+    let hardest = (options.output_mode == OutputMode::Summary && options.chart.is_none())
+        .then(|| hardest_chart(simfile_charts(&simfile_data)))
+        .flatten();
+    let chart = match hardest {
+        Some(chart) => chart,
+        None => select_chart(&simfile_data, &sections, options.chart.as_ref())?,
+    };
+    let bare_notes_block = is_bare_notes_block(&simfile_data, &sections);
+
+    // Fast path for library indexing: only the note counts are wanted, so skip
+    // timing, hashing, breakdowns, NPS and pattern analysis entirely. Every
+    // chart is counted unless --chart picks one.
+    if options.counts_only {
+        let charts = if options.chart.is_some() || bare_notes_block {
+            vec![chart]
+        } else {
+            simfile_charts(&simfile_data)
+        };
+        return print_note_counts(&title_str, &charts, options.output_mode, start_time);
+    }
 
-    let notes_bytes = notes_opt.unwrap_or(b"<invalid-notes>");
-    let (fields, chart_data) = split_notes_fields(notes_bytes);
-    if fields.len() < 5 {
-        eprintln!("#NOTES section is incomplete.");
-        std::process::exit(1);
+    // SSC charts may carry their own timing, which wins over the song's.
+    sections.apply_chart_timing(&extract_sections(chart.chart_tags)?);
+
+    let bpms_raw = match options.bpms.as_deref() {
+        Some(bpms) => bpms,
+        None => std::str::from_utf8(sections.bpms.unwrap_or(b"<invalid-bpms>"))
+            .unwrap_or("<invalid-bpms>"),
+    };
+    let mut normalized_bpms = normalize_float_digits(bpms_raw);
+    if !options.keep_bpm_segments {
+        normalized_bpms = coalesce_bpm_segments(&normalized_bpms);
+    }
+
+    let step_type_str  = chart.field(0);
+    let difficulty_str = chart.field(2);
+    if options.verbose {
+        if let Err(e) = Difficulty::try_from(difficulty_str) {
+            eprintln!("Warning: {}", e);
+        }
     }
+    let difficulty_normalized = normalize_difficulty_label(difficulty_str);
+    let rating_str     = chart.field(3);
 
-    let step_type_str  = std::str::from_utf8(fields[0]).unwrap_or("").trim();
-    let difficulty_str = std::str::from_utf8(fields[2]).unwrap_or("").trim();
-    let rating_str     = std::str::from_utf8(fields[3]).unwrap_or("").trim();
+    let chart_data = chart.notes;
+    let (mut minimized_chart, mut stats, measure_densities) = minimize_chart_and_count(chart_data);
+
+    let timing = TimingData::parse(&TimingTags {
+        bpms: &normalized_bpms,
+        time_signatures: std::str::from_utf8(sections.time_signatures.unwrap_or(b"")).unwrap_or(""),
+        fakes: std::str::from_utf8(sections.fakes.unwrap_or(b"")).unwrap_or(""),
+        stops: std::str::from_utf8(sections.stops.unwrap_or(b"")).unwrap_or(""),
+        delays: std::str::from_utf8(sections.delays.unwrap_or(b"")).unwrap_or(""),
+        display_bpm: std::str::from_utf8(sections.display_bpm.unwrap_or(b"")).unwrap_or(""),
+    });
+    count_timed_arrow_stats(&mut stats, &minimized_chart, &timing, measure_densities.len());
 
-    let (mut minimized_chart, stats, measure_densities) = minimize_chart_and_count(chart_data);
 
     if let Some(pos) = minimized_chart.iter().rposition(|&b| b != b'\n') {
         minimized_chart.truncate(pos + 1);
     }
 
-    let stream_counts = compute_stream_counts(&measure_densities);
+    // Print the canonical note data for callers doing their own hashing.
+    if options.print_minimized {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&minimized_chart)?;
+        stdout.write_all(b"\n")?;
+        return Ok(());
+    }
+
+    // Hash
+    let hash_hex = if options.hash_ignore_mines {
+        groovestats_hash(&chart_without_mines(&minimized_chart), &normalized_bpms)
+    } else {
+        groovestats_hash(&minimized_chart, &normalized_bpms)
+    };
+    let short_hash = &hash_hex[..16];
+    let rhythm_fingerprint = rhythm_fingerprint(&minimized_chart);
+    let duplicate_of = find_duplicate_chart(&simfile_charts(&simfile_data), &chart);
+
+    // With --measures, everything from here on looks at just those measures;
+    // the hash above and the arrow counts stay whole-chart.
+    let total_measures = measure_densities.len();
+    let (range_start, range_end) = match options.measure_range {
+        Some((start, end)) => {
+            let end = end.min(total_measures);
+            (start.min(end), end)
+        }
+        None => (0, total_measures),
+    };
+    if options.measure_range.is_some() {
+        minimized_chart = select_measures(&minimized_chart, range_start, range_end);
+    }
+    let measure_densities = &measure_densities[range_start..range_end];
+
+    // Just the breakdown, for callers that want nothing else: skips NPS and
+    // pattern analysis.
+    if let Some(mode) = options.breakdown_only {
+        let breakdown = if mode == BreakdownMode::Annotated {
+            let measure_beats = &timing.measure_beats(range_end)[range_start..];
+            generate_annotated_breakdown(measure_densities, measure_beats, &timing.bpm_map, &options.stream_thresholds)
+        } else {
+            generate_breakdown(measure_densities, mode, &options.stream_thresholds)
+        };
+        println!("{}", breakdown);
+        return Ok(());
+    }
+
+    let mut stream_counts = compute_stream_counts(measure_densities, &options.stream_thresholds);
+    let measure_mine_rows = compute_measure_mine_rows(&minimized_chart);
+    stream_counts.stream_mines = count_stream_mines(
+        measure_densities,
+        &measure_mine_rows,
+        &options.stream_thresholds,
+    );
+    stream_counts.gallop_measures = count_gallop_measures(&minimized_chart);
+    let playfield_heatmap = options
+        .playfield_heatmap
+        .then(|| compute_playfield_heatmap(&minimized_chart, measure_densities.len()));
 
     // Compute total_streams as the sum of individual stream counts
     let total_streams = stream_counts.run16_streams
@@ -1219,35 +3512,143 @@ fn main() -> io::Result<()> {
     + stream_counts.run24_streams
     + stream_counts.run32_streams;
 
-    let detailed = generate_breakdown(&measure_densities, BreakdownMode::Detailed);
-    let partial  = generate_breakdown(&measure_densities, BreakdownMode::Partial);
-    let simple   = generate_breakdown(&measure_densities, BreakdownMode::Simplified);
-
-    // Hash
-    let mut hasher = Sha1::new();
-    hasher.update(&minimized_chart);
-    hasher.update(normalized_bpms.as_bytes());
-    let hash_result = hasher.finalize();
-    let hash_hex = hex::encode(hash_result);
-    let short_hash = &hash_hex[..16];
+    let detailed = generate_breakdown(measure_densities, BreakdownMode::Detailed, &options.stream_thresholds);
+    let partial  = generate_breakdown(measure_densities, BreakdownMode::Partial, &options.stream_thresholds);
+    let simple   = generate_breakdown(measure_densities, BreakdownMode::Simplified, &options.stream_thresholds);
+    let stream_sequences = compute_stream_sequences(measure_densities, &options.stream_thresholds, range_start);
 
-    // BPM map and range
-    let bpm_map = parse_bpm_map(&normalized_bpms);
-    let (min_bpm, max_bpm) = compute_bpm_range(&bpm_map);
+    // BPM range
+    let (min_bpm, max_bpm) = compute_bpm_range(&timing.bpm_map);
 
     // NPS vector + stats
-    let measure_nps_vec = compute_measure_nps_vec(&measure_densities, &bpm_map);
+    let measure_beats = timing.measure_beats(range_end)[range_start..].to_vec();
+    let mut nps_measure_beats = measure_beats.clone();
+    if let Some(last) = nps_measure_beats.last_mut().filter(|_| range_end == total_measures) {
+        last.1 = final_measure_span(chart_data, last.1);
+    }
+    let measure_nps_vec = compute_measure_nps_vec(measure_densities, &nps_measure_beats, &timing.bpm_map);
+    let measure_profiles = options.measure_profiles.then(|| {
+        compute_measure_profiles(
+            &minimized_chart,
+            measure_densities,
+            &measure_nps_vec,
+            &options.stream_thresholds,
+            range_start,
+        )
+    });
+    let note_rows = collect_note_rows(&minimized_chart, &measure_beats);
+    let suspicious_jumps = find_suspicious_jumps(&note_rows);
+    if !suspicious_jumps.is_empty() && options.verbose {
+        let rows: Vec<String> = suspicious_jumps
+            .iter()
+            .map(|&row| format!("{} (beat {})", row, note_row_to_beat(row)))
+            .collect();
+        eprintln!("Warning: lone jumps in single-note stream at rows {}", rows.join(", "));
+    }
+    let notes_weighted_bpm = compute_notes_weighted_bpm(&note_rows, &timing.bpm_map);
+    let quantization_colors = options
+        .quantization
+        .then(|| compute_quantization_colors(&note_rows));
+    let parse_seconds = |tag: Option<&[u8]>| {
+        std::str::from_utf8(tag.unwrap_or(b""))
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .unwrap_or(0.0)
+    };
+    let chart_timing = compute_chart_timing(
+        &note_rows,
+        &timing,
+        parse_seconds(sections.offset),
+        parse_seconds(sections.sample_start),
+        parse_seconds(sections.sample_length),
+    );
+    let stream_span = compute_stream_span(
+        measure_densities,
+        &measure_beats,
+        &options.stream_thresholds,
+        &timing,
+        chart_timing.offset,
+        range_start,
+    );
+    // Song length as declared by the file, falling back to the last-second hint
+    // that marks where the song ends; only reported when the file has either.
+    let music_length = sections
+        .music_length
+        .or(sections.last_second_hint)
+        .and_then(|tag| std::str::from_utf8(tag).ok())
+        .and_then(|v| v.trim().parse::<f64>().ok());
     let (max_nps, median_nps) = get_nps_stats(&measure_nps_vec);
+    let active_median_nps = active_median_nps(&measure_nps_vec, measure_densities, &options.stream_thresholds);
+    let stamina_score = compute_stamina_score(&measure_nps_vec, &nps_measure_beats, &timing.bpm_map);
+    // Graph data for frontends that render their own density graph.
+    if options.graph_json {
+        let measure_nps: Vec<Value> = measure_nps_vec
+            .iter()
+            .map(|&nps| json_fixed(nps, options.precision))
+            .collect();
+        let report = json!({
+            "file": simfile_path,
+            "charts": [{
+                "step_type": step_type_str,
+                "difficulty": difficulty_str,
+                "hash_short": short_hash,
+                "max_nps": json_fixed(max_nps, options.precision),
+                "measure_nps": measure_nps,
+            }],
+        });
+        return print_json(report, options.output_mode);
+    }
+
+    let sampled_nps = options.nps_at.map(|t| (t, nps_at_second(t, chart_timing.offset, &timing, &measure_beats, &measure_nps_vec)));
 
     // Chart length (seconds) as int
-    let total_length = compute_total_chart_length(&measure_densities, &bpm_map);
+    let total_length = compute_total_chart_length(&measure_beats, &timing);
+    // Debug builds re-derive the length from the timing model itself; the
+    // per-measure sum above takes each measure's tempo from its first beat.
+    if cfg!(debug_assertions) && options.verbose {
+        if let (Some(&(first_beat, _)), Some(&(last_beat, beats))) = (measure_beats.first(), measure_beats.last()) {
+            let derived = timing.second_at_beat(last_beat + beats) - timing.second_at_beat(first_beat);
+            if (derived - total_length as f64).abs() > 1.0 {
+                eprintln!(
+                    "Warning: chart length {}s disagrees with {:.2}s derived from timing",
+                    total_length, derived
+                );
+            }
+        }
+    }
+
+    let orphan_tail_beats = find_orphan_tail(measure_densities, &measure_beats);
+    if let (Some(gap), true) = (orphan_tail_beats, options.verbose) {
+        eprintln!("Warning: the last notes come {} empty beats after the rest of the chart", gap);
+    }
 
     // Pattern stats
     let bitmasks = parse_bitmask_chart(&minimized_chart);
-    let pattern_stats = do_pattern_analysis(&bitmasks, stats.total_arrows);
+    // Pattern ratios are relative to the measures being looked at.
+    let (pattern_arrows, pattern_steps) = if options.measure_range.is_some() {
+        let (_, range_stats, _) = minimize_chart_and_count(&minimized_chart);
+        (range_stats.total_arrows, range_stats.total_steps)
+    } else {
+        (stats.total_arrows, stats.total_steps)
+    };
+    let pattern_stats = do_pattern_analysis(
+        &bitmasks,
+        pattern_arrows,
+        options.min_drill,
+        options.candle_denominator,
+    );
+    let patterns_per_1k_notes = pattern_stats.per_1k_notes(pattern_steps);
+    let custom_pattern_counts: Vec<(&str, u32)> = options
+        .custom_patterns
+        .iter()
+        .map(|(pattern, atoms)| (pattern.as_str(), count_custom_pattern(&bitmasks, atoms)))
+        .collect();
+    let candle_runs = options
+        .collect_candle_runs
+        .then(|| collect_candle_runs(&bitmasks));
 
     // Generate PNG if requested (but DO NOT return yet).
-    if generate_png {
+    if options.generate_png {
         // The updated function returns io::Result, so propagate the error directly
         generate_density_graph_png(&measure_nps_vec, max_nps, short_hash)?;
     }
@@ -1255,93 +3656,209 @@ fn main() -> io::Result<()> {
     // Finally, print elapsed time at the END
     let elapsed = start_time.elapsed();
 
-    // Now do JSON or text output (or neither).
-    if generate_json {
-        println!("{{");
-        // We place elapsed time at the END, so skip for now.
-
-        // Basic info
-        println!("  \"title\": \"{}\",", escape_json(&title_str));
-        println!("  \"title_translit\": \"{}\",", escape_json(titletranslit_str));
-        println!("  \"subtitle\": \"{}\",", escape_json(subtitle_str));
-        println!("  \"subtitle_translit\": \"{}\",", escape_json(subtitletranslit_str));
-        println!("  \"artist\": \"{}\",", escape_json(artist_str));
-        println!("  \"artist_translit\": \"{}\",", escape_json(artisttranslit_str));
-        println!("  \"bpms\": \"{}\",", escape_json(&normalized_bpms));
-        println!("  \"step_type\": \"{}\",", escape_json(step_type_str));
-        println!("  \"difficulty\": \"{}\",", escape_json(difficulty_str));
-        println!("  \"rating\": \"{}\",", escape_json(rating_str));
-        println!("  \"hash_short\": \"{}\",", short_hash);
-
-        // Arrow Stats
-        println!("  \"arrow_stats\": {{");
-        println!("     \"left\": {},", stats.left);
-        println!("     \"down\": {},", stats.down);
-        println!("     \"up\": {},", stats.up);
-        println!("     \"right\": {},", stats.right);
-        println!("     \"total_arrows\": {},", stats.total_arrows);
-        println!("     \"total_steps\": {},", stats.total_steps);
-        println!("     \"jumps\": {},", stats.jumps);
-        println!("     \"hands\": {},", stats.hands);
-        println!("     \"holds\": {},", stats.holds);
-        println!("     \"rolls\": {},", stats.rolls);
-        println!("     \"mines\": {}", stats.mines);
-        println!("  }},");
-
-        // Stream Counts
-        println!("  \"stream_counts\": {{");
-        println!("     \"run16_streams\": {},", stream_counts.run16_streams);
-        println!("     \"run20_streams\": {},", stream_counts.run20_streams);
-        println!("     \"run24_streams\": {},", stream_counts.run24_streams);
-        println!("     \"run32_streams\": {},", stream_counts.run32_streams);
-        println!("     \"total_streams\": {},", total_streams);
-        println!("     \"total_breaks\": {}", stream_counts.total_breaks);
-        println!("  }},");
-
-        // Breakdown
-        println!("  \"breakdown\": {{");
-        println!("     \"detailed\": \"{}\",", escape_json(&detailed));
-        println!("     \"partial\": \"{}\",", escape_json(&partial));
-        println!("     \"simple\": \"{}\"", escape_json(&simple));
-        println!("  }},");
-
-        // BPM info
-        println!("  \"bpm_info\": {{");
-        println!("     \"min_bpm\": {:.2},", min_bpm);
-        println!("     \"max_bpm\": {:.2},", max_bpm);
-        println!("     \"chart_length_s\": {},", total_length);
-        println!("     \"max_nps\": {:.2},", max_nps);
-        println!("     \"median_nps\": {:.2}", median_nps);
-        println!("  }},");
-
-        // Pattern stats
-        println!("  \"pattern_stats\": {{");
-        println!("     \"left_foot_candles\": {},", pattern_stats.left_foot_candles);
-        println!("     \"right_foot_candles\": {},", pattern_stats.right_foot_candles);
-        println!("     \"total_candles\": {},", pattern_stats.total_candles);
-        println!("     \"candles_percent\": {:.2},", pattern_stats.candles_percent);
-        println!("     \"ld_ru_mono\": {},", pattern_stats.ld_ru_mono);
-        println!("     \"lu_rd_mono\": {},", pattern_stats.lu_rd_mono);
-        println!("     \"mono_percent\": {:.2},", pattern_stats.mono_percent);
-        println!("     \"lr_boxes\": {},", pattern_stats.lr_boxes);
-        println!("     \"ud_boxes\": {},", pattern_stats.ud_boxes);
-        println!("     \"corner_ld_boxes\": {},", pattern_stats.corner_ld_boxes);
-        println!("     \"corner_lu_boxes\": {},", pattern_stats.corner_lu_boxes);
-        println!("     \"corner_rd_boxes\": {},", pattern_stats.corner_rd_boxes);
-        println!("     \"corner_ru_boxes\": {},", pattern_stats.corner_ru_boxes);
-        println!("     \"anchor_left\": {},", pattern_stats.anchor_left);
-        println!("     \"anchor_down\": {},", pattern_stats.anchor_down);
-        println!("     \"anchor_up\": {},", pattern_stats.anchor_up);
-        println!("     \"anchor_right\": {},", pattern_stats.anchor_right);
-        println!("     \"right_dorito\": {},", pattern_stats.right_dorito);
-        println!("     \"left_dorito\": {},", pattern_stats.left_dorito);
-        println!("     \"inv_right_dorito\": {},", pattern_stats.inv_right_dorito);
-        println!("     \"inv_left_dorito\": {}", pattern_stats.inv_left_dorito);
-        println!("  }},");
+    // Now do summary, CSV, JSON or text output.
+    if options.output_mode == OutputMode::Summary {
+        // The title goes last so the fixed-width columns before it line up.
+        println!(
+            "{:<10} {:>3} {:>7.2} nps {:>5}s  {}",
+            difficulty_str, rating_str, max_nps, total_length, title_str
+        );
+    } else if options.output_mode == OutputMode::Csv {
+        let header = [
+            "title", "artist", "step_type", "difficulty", "rating", "hash_short", "taps", "hold_heads", "roll_heads",
+            "mines", "lifts", "fakes", "total_steps", "jumps", "hands", "chart_length_s", "max_nps", "median_nps",
+        ];
+        let row = [
+            title_str.to_string(),
+            artist_str.to_string(),
+            step_type_str.to_string(),
+            difficulty_str.to_string(),
+            rating_str.to_string(),
+            short_hash.to_string(),
+            stats.taps.to_string(),
+            stats.holds.to_string(),
+            stats.rolls.to_string(),
+            stats.mines.to_string(),
+            stats.lifts.to_string(),
+            stats.fakes.to_string(),
+            stats.total_steps.to_string(),
+            stats.jumps.to_string(),
+            stats.hands.to_string(),
+            total_length.to_string(),
+            format!("{:.2}", max_nps),
+            format!("{:.2}", median_nps),
+        ];
+        println!("{}", csv_line(header, options.csv_delimiter));
+        println!("{}", csv_line(row.iter().map(String::as_str), options.csv_delimiter));
+    } else if matches!(options.output_mode, OutputMode::Json | OutputMode::JsonPretty) {
+        let precision = options.precision;
+        let mut report = json!({
+            // Basic info
+            "title": title_str,
+            "title_translit": titletranslit_str,
+            "subtitle": subtitle_str,
+            "subtitle_translit": subtitletranslit_str,
+            "artist": artist_str,
+            "artist_translit": artisttranslit_str,
+            "bpms": normalized_bpms,
+            "bg_changes": bg_changes_str,
+            "fg_changes": fg_changes_str,
+            "has_video_background": has_video_background,
+            "step_type": step_type_str,
+            "difficulty": difficulty_str,
+            "difficulty_normalized": difficulty_normalized,
+            "rating": rating_str,
+            "hash_short": short_hash,
+            "rhythm_fingerprint": rhythm_fingerprint,
+            "arrow_stats": arrow_stats_json(&stats, true),
+            "stream_counts": {
+                "run16_streams": stream_counts.run16_streams,
+                "run20_streams": stream_counts.run20_streams,
+                "run24_streams": stream_counts.run24_streams,
+                "run32_streams": stream_counts.run32_streams,
+                "total_streams": total_streams,
+                "total_breaks": stream_counts.total_breaks,
+                "unused_measures": stream_counts.unused_measures,
+                "stream_mines": stream_counts.stream_mines,
+                "gallop_measures": stream_counts.gallop_measures,
+            },
+            "breakdown": {
+                "detailed": detailed,
+                "partial": partial,
+                "simple": simple,
+            },
+            "stream_sequences": stream_sequences
+                .iter()
+                .map(|s| json!({ "stream_start": s.stream_start, "stream_end": s.stream_end, "is_break": s.is_break }))
+                .collect::<Vec<_>>(),
+            "bpm_info": {
+                "min_bpm": min_bpm,
+                "max_bpm": max_bpm,
+                "display_bpm": timing.display_bpm_string(),
+                "chart_length_s": total_length,
+                "max_nps": json_fixed(max_nps, precision),
+                "median_nps": json_fixed(median_nps, precision),
+                "active_median_nps": json_fixed(active_median_nps, precision),
+                "stamina_score": json_fixed(stamina_score, precision),
+                "notes_weighted_bpm": json_fixed(notes_weighted_bpm, precision),
+            },
+        });
+        let fields = report.as_object_mut().expect("report is a JSON object");
+
+        fields.insert(
+            "pattern_stats".to_string(),
+            json!({
+                "left_foot_candles": pattern_stats.left_foot_candles,
+                "right_foot_candles": pattern_stats.right_foot_candles,
+                "total_candles": pattern_stats.total_candles,
+                "candles_percent": json_fixed(pattern_stats.candles_percent, precision),
+                "ld_ru_mono": pattern_stats.ld_ru_mono,
+                "lu_rd_mono": pattern_stats.lu_rd_mono,
+                "mono_percent": json_fixed(pattern_stats.mono_percent, precision),
+                "lr_boxes": pattern_stats.lr_boxes,
+                "ud_boxes": pattern_stats.ud_boxes,
+                "corner_ld_boxes": pattern_stats.corner_ld_boxes,
+                "corner_lu_boxes": pattern_stats.corner_lu_boxes,
+                "corner_rd_boxes": pattern_stats.corner_rd_boxes,
+                "corner_ru_boxes": pattern_stats.corner_ru_boxes,
+                "anchor_left": pattern_stats.anchor_left,
+                "anchor_down": pattern_stats.anchor_down,
+                "anchor_up": pattern_stats.anchor_up,
+                "anchor_right": pattern_stats.anchor_right,
+                "right_dorito": pattern_stats.right_dorito,
+                "left_dorito": pattern_stats.left_dorito,
+                "inv_right_dorito": pattern_stats.inv_right_dorito,
+                "inv_left_dorito": pattern_stats.inv_left_dorito,
+                "drills": pattern_stats.drills,
+                "longest_drill": pattern_stats.longest_drill,
+            }),
+        );
+
+        let per_1k: serde_json::Map<String, Value> = patterns_per_1k_notes
+            .iter()
+            .map(|&(name, value)| (name.to_string(), json_fixed(value, precision)))
+            .collect();
+        fields.insert("patterns_per_1k_notes".to_string(), per_1k.into());
+
+        fields.insert(
+            "chart_timing".to_string(),
+            json!({
+                "offset": json_number(chart_timing.offset),
+                "sample_start": json_number(chart_timing.sample_start),
+                "sample_length": json_number(chart_timing.sample_length),
+                "first_note_second": json_fixed(chart_timing.first_note_second, precision),
+                "last_note_second": json_fixed(chart_timing.last_note_second, precision),
+                "stream_onset_measure": stream_span.as_ref().map(|span| span.onset_measure),
+                "stream_onset_second": stream_span.as_ref().map(|span| json_fixed(span.onset_second, precision)),
+                "stream_end_measure": stream_span.as_ref().map(|span| span.end_measure),
+                "stream_end_second": stream_span.as_ref().map(|span| json_fixed(span.end_second, precision)),
+            }),
+        );
+
+        if !custom_pattern_counts.is_empty() {
+            let counts: serde_json::Map<String, Value> = custom_pattern_counts
+                .iter()
+                .map(|&(pattern, count)| (pattern.to_string(), json!(count)))
+                .collect();
+            fields.insert("custom_patterns".to_string(), counts.into());
+        }
+
+        if let Some(runs) = &candle_runs {
+            fields.insert("candle_runs".to_string(), json!(runs));
+        }
+
+        if let Some(heatmap) = &playfield_heatmap {
+            fields.insert("playfield_heatmap".to_string(), json!(heatmap));
+        }
+
+        if let Some(profiles) = &measure_profiles {
+            let profiles: Vec<Value> = profiles
+                .iter()
+                .map(|p| {
+                    json!({
+                        "index": p.index,
+                        "nps": json_fixed(p.nps, precision),
+                        "is_stream": p.is_stream,
+                        "jacks": p.jacks,
+                    })
+                })
+                .collect();
+            fields.insert("measure_profiles".to_string(), profiles.into());
+        }
+
+        if let Some(colors) = &quantization_colors {
+            fields.insert("quantization".to_string(), json!(colors));
+        }
+
+        if let Some((second, nps)) = sampled_nps {
+            fields.insert(
+                "nps_at_second".to_string(),
+                json!({ "second": json_number(second), "nps": json_fixed(nps, precision) }),
+            );
+        }
+
+        if let Some(seconds) = music_length {
+            fields.insert("music_length_seconds".to_string(), json_number(seconds));
+        }
+
+        fields.insert("suspicious_jumps".to_string(), json!(suspicious_jumps));
+
+        if let Some(gap) = orphan_tail_beats {
+            fields.insert("orphan_tail_beats".to_string(), json_number(gap));
+        }
+
+        if let Some(index) = duplicate_of {
+            fields.insert("duplicate_of".to_string(), json!(index));
+        }
+
+        if options.include_notes {
+            let notes = String::from_utf8_lossy(&minimized_chart);
+            fields.insert("minimized_note_data".to_string(), json!(notes));
+        }
 
         // Execution time
-        println!("  \"elapsed\": \"{:?}\"", elapsed);
-        println!("}}");
+        fields.insert("elapsed".to_string(), json!(format!("{:?}", elapsed)));
+        print_json(report, options.output_mode)?;
     } else {
         println!("Title: {}", title_str);
         println!("Title translate: {}", titletranslit_str);
@@ -1350,23 +3867,17 @@ fn main() -> io::Result<()> {
         println!("Artist: {}", artist_str);
         println!("Artist translate: {}", artisttranslit_str);
         println!("Normalized BPMs: {}", normalized_bpms);
+        println!("BG changes: {}", bg_changes_str);
+        println!("FG changes: {}", fg_changes_str);
+        println!("Video background: {}", if has_video_background { "yes" } else { "no" });
         println!("Steptype: {}", step_type_str);
         println!("Difficulty: {}", difficulty_str);
+        println!("Difficulty (normalized): {}", difficulty_normalized);
         println!("Rating: {}", rating_str);
         println!("Hash (first 16 hex chars): {}", short_hash);
+        println!("Rhythm fingerprint: {}", rhythm_fingerprint);
 
-        println!("--- Arrow Stats ---");
-        println!("Left: {}", stats.left);
-        println!("Down: {}", stats.down);
-        println!("Up: {}", stats.up);
-        println!("Right: {}", stats.right);
-        println!("Total arrows: {}", stats.total_arrows);
-        println!("Total steps: {}", stats.total_steps);
-        println!("Jumps (2-arrow steps): {}", stats.jumps);
-        println!("Hands (3+ arrow steps): {}", stats.hands);
-        println!("Holds: {}", stats.holds);
-        println!("Rolls: {}", stats.rolls);
-        println!("Mines: {}", stats.mines);
+        print_arrow_stats_text(&stats, true);
 
         println!("--- Stream Counts ---");
         println!("16th streams: {}", stream_counts.run16_streams);
@@ -1375,6 +3886,9 @@ fn main() -> io::Result<()> {
         println!("32nd streams: {}", stream_counts.run32_streams);
         println!("Total streams: {}", total_streams);
         println!("Total breaks: {}", stream_counts.total_breaks);
+        println!("Unused measures: {}", stream_counts.unused_measures);
+        println!("Stream mines: {}", stream_counts.stream_mines);
+        println!("Gallop measures: {}", stream_counts.gallop_measures);
 
         println!("Detailed breakdown: {}", detailed);
         println!("Partially simplified: {}", partial);
@@ -1383,9 +3897,13 @@ fn main() -> io::Result<()> {
         println!("--- Additional Chart Info ---");
         println!("Min BPM: {:.2}", min_bpm);
         println!("Max BPM: {:.2}", max_bpm);
+        println!("Display BPM: {}", timing.display_bpm_string());
         println!("Chart length (seconds): {}", total_length);
         println!("Max NPS: {:.2}", max_nps);
         println!("Median NPS: {:.2}", median_nps);
+        println!("Active median NPS: {:.2}", active_median_nps);
+        println!("Stamina score: {:.2}", stamina_score);
+        println!("Notes-weighted BPM: {:.2}", notes_weighted_bpm);
 
         println!("--- Pattern Stats ---");
         println!("left_foot_candles: {}", pattern_stats.left_foot_candles);
@@ -1409,6 +3927,63 @@ fn main() -> io::Result<()> {
         println!("left_dorito: {}", pattern_stats.left_dorito);
         println!("inv_right_dorito: {}", pattern_stats.inv_right_dorito);
         println!("inv_left_dorito: {}", pattern_stats.inv_left_dorito);
+        println!("drills: {}", pattern_stats.drills);
+        println!("longest_drill: {}", pattern_stats.longest_drill);
+        println!("--- Patterns per 1000 Notes ---");
+        for &(name, value) in &patterns_per_1k_notes {
+            println!("{}: {:.2}", name, value);
+        }
+        println!("--- Chart Timing ---");
+        println!("Offset: {}", chart_timing.offset);
+        println!("Sample start: {}", chart_timing.sample_start);
+        println!("Sample length: {}", chart_timing.sample_length);
+        println!("First note (seconds): {:.2}", chart_timing.first_note_second);
+        println!("Last note (seconds): {:.2}", chart_timing.last_note_second);
+        if let Some(span) = &stream_span {
+            println!("Stream onset: measure {} ({:.2}s)", span.onset_measure, span.onset_second);
+            println!("Stream end: measure {} ({:.2}s)", span.end_measure, span.end_second);
+        }
+
+        for &(pattern, count) in &custom_pattern_counts {
+            println!("Custom pattern {}: {}", pattern, count);
+        }
+        if let Some(runs) = &candle_runs {
+            let runs: Vec<String> = runs
+                .iter()
+                .map(|&(start, len)| format!("{}x{}", start, len))
+                .collect();
+            println!("Candle runs (row x candles): {}", runs.join(" "));
+        }
+        if let Some(heatmap) = &playfield_heatmap {
+            println!("Playfield heatmap (notes per measure):");
+            for (lane, counts) in heatmap.iter().enumerate() {
+                let counts: Vec<String> = counts.iter().map(|c| c.to_string()).collect();
+                println!("  lane {}: {}", lane, counts.join(" "));
+            }
+        }
+        if let Some(profiles) = &measure_profiles {
+            println!("Measure profiles:");
+            for p in profiles {
+                let kind = if p.is_stream { "stream" } else { "break" };
+                println!("  measure {}: {:.2} nps, {}, {} jacks", p.index, p.nps, kind, p.jacks);
+            }
+        }
+        if let Some(colors) = &quantization_colors {
+            let colors: Vec<String> = colors
+                .iter()
+                .map(|&(row, color)| format!("{}:{}", row, color))
+                .collect();
+            println!("Quantization (row:color): {}", colors.join(" "));
+        }
+        if let Some((second, nps)) = sampled_nps {
+            println!("NPS at {}s: {:.2}", second, nps);
+        }
+        if let Some(seconds) = music_length {
+            println!("Music length (seconds): {}", seconds);
+        }
+        if let Some(index) = duplicate_of {
+            println!("Duplicate of chart: {}", index);
+        }
         println!("---");
         println!("Elapsed time: {:?}", elapsed);
     }
@@ -1416,18 +3991,818 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
-/// Minimal “escape” function for JSON strings (handle quotes, backslashes, etc.).
-fn escape_json(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    for c in input.chars() {
-        match c {
-            '"' => out.push_str("\\\""),
-            '\\' => out.push_str("\\\\"),
-            '\n' => out.push_str("\\n"),
-            '\r' => out.push_str("\\r"),
-            '\t' => out.push_str("\\t"),
-            _ => out.push(c),
+/// Returns the value following `flag` on the command line, if any.
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// Returns the values following every occurrence of `flag` on the command line.
+fn arg_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+/// Prints a JSON report: in the layout `--json` has always used, or indented
+/// with 2 spaces and keys sorted for `--json-pretty`, which keeps diffs of
+/// saved outputs stable.
+fn print_json(mut report: Value, mode: OutputMode) -> io::Result<()> {
+    if mode == OutputMode::JsonPretty {
+        report.sort_all_objects();
+        println!("{}", serde_json::to_string_pretty(&report).map_err(io::Error::other)?);
+    } else {
+        println!("{}", json_layout(&report));
+    }
+    Ok(())
+}
+
+/// The `--json` layout: a line per top-level member, a line per member of the
+/// objects one level down (indented 5 spaces), and anything deeper, arrays
+/// included, on a single line.
+fn json_layout(report: &Value) -> String {
+    let Value::Object(fields) = report else {
+        return json_inline(report);
+    };
+    let members: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| match value {
+            Value::Object(inner) if !inner.is_empty() => {
+                let inner: Vec<String> = inner
+                    .iter()
+                    .map(|(key, value)| format!("     {}: {}", Value::from(key.as_str()), json_inline(value)))
+                    .collect();
+                format!("  {}: {{\n{}\n  }}", Value::from(key.as_str()), inner.join(",\n"))
+            }
+            _ => format!("  {}: {}", Value::from(key.as_str()), json_inline(value)),
+        })
+        .collect();
+    format!("{{\n{}\n}}", members.join(",\n"))
+}
+
+/// `value` on one line, with a space after each comma and inside braces.
+fn json_inline(value: &Value) -> String {
+    match value {
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(json_inline).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(fields) if !fields.is_empty() => {
+            let members: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("{}: {}", Value::from(key.as_str()), json_inline(value)))
+                .collect();
+            format!("{{ {} }}", members.join(", "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// A JSON number with exactly `precision` decimals, so `--precision 3` prints
+/// `10.000` rather than `10.0`. Non-finite values become `null`.
+fn json_fixed(value: f64, precision: usize) -> Value {
+    format!("{:.*}", precision, value)
+        .parse::<serde_json::Number>()
+        .map_or(Value::Null, Value::Number)
+}
+
+/// A JSON number written the way Rust displays `value`, so `3.0` prints as
+/// `3` like in the text output. Non-finite values become `null`.
+fn json_number(value: f64) -> Value {
+    value.to_string().parse::<serde_json::Number>().map_or(Value::Null, Value::Number)
+}
+
+/// The `arrow_stats` JSON object. `timed` adds the stats that need the timing
+/// tags, which `--counts-only` never reads.
+fn arrow_stats_json(stats: &ArrowStats, timed: bool) -> Value {
+    let mut json = json!({
+        "left": stats.left,
+        "down": stats.down,
+        "up": stats.up,
+        "right": stats.right,
+        "total_arrows": stats.total_arrows,
+        "total_steps": stats.total_steps,
+        "jumps": stats.jumps,
+        "hands": stats.hands,
+        "holds": stats.holds,
+        "rolls": stats.rolls,
+        "mines": stats.mines,
+    });
+    if let (true, Some(fields)) = (timed, json.as_object_mut()) {
+        fields.insert("faked_notes".to_string(), json!(stats.faked_notes));
+        fields.insert("guide_mines".to_string(), json!(stats.guide_mines));
+    }
+    json
+}
+
+fn print_arrow_stats_text(stats: &ArrowStats, timed: bool) {
+    println!("--- Arrow Stats ---");
+    println!("Left: {}", stats.left);
+    println!("Down: {}", stats.down);
+    println!("Up: {}", stats.up);
+    println!("Right: {}", stats.right);
+    println!("Total arrows: {}", stats.total_arrows);
+    println!("Total steps: {}", stats.total_steps);
+    println!("Jumps (2-arrow steps): {}", stats.jumps);
+    println!("Hands (3+ arrow steps): {}", stats.hands);
+    println!("Holds: {}", stats.holds);
+    println!("Rolls: {}", stats.rolls);
+    println!("Mines: {}", stats.mines);
+    if timed {
+        println!("Faked notes: {}", stats.faked_notes);
+        println!("Guide mines: {}", stats.guide_mines);
+    }
+}
+
+/// Prints the `--counts-only` report: the arrow stats of each of `charts`.
+/// Faked notes and guide mines need the timing tags, so they are left out.
+fn print_note_counts(title: &str, charts: &[ChartBlock], output_mode: OutputMode, start_time: Instant) -> io::Result<()> {
+    let counts: Vec<(&ChartBlock, ArrowStats)> = charts
+        .iter()
+        .map(|chart| (chart, minimize_chart_and_count(chart.notes).1))
+        .collect();
+    let elapsed = start_time.elapsed();
+    if matches!(output_mode, OutputMode::Json | OutputMode::JsonPretty) {
+        let charts: Vec<Value> = counts
+            .iter()
+            .map(|(chart, stats)| {
+                json!({
+                    "step_type": chart.field(0),
+                    "difficulty": chart.field(2),
+                    "difficulty_normalized": normalize_difficulty_label(chart.field(2)),
+                    "rating": chart.field(3),
+                    "arrow_stats": arrow_stats_json(stats, false),
+                })
+            })
+            .collect();
+        let report = json!({ "title": title, "charts": charts, "elapsed": format!("{:?}", elapsed) });
+        print_json(report, output_mode)?;
+    } else {
+        println!("Title: {}", title);
+        for (chart, stats) in &counts {
+            println!("--- Chart ---");
+            println!("Steptype: {}", chart.field(0));
+            println!("Difficulty: {}", chart.field(2));
+            println!("Difficulty (normalized): {}", normalize_difficulty_label(chart.field(2)));
+            println!("Rating: {}", chart.field(3));
+            print_arrow_stats_text(stats, false);
+        }
+        println!("---");
+        println!("Elapsed time: {:?}", elapsed);
+    }
+    Ok(())
+}
+
+/// Quotes a CSV field per RFC 4180 when it contains the delimiter, a quote or
+/// a line break, doubling any quotes inside.
+fn escape_csv(input: &str, delimiter: char) -> String {
+    if input.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", input.replace('"', "\"\""))
+    } else {
+        input.to_string()
+    }
+}
+
+fn csv_line<'a>(fields: impl IntoIterator<Item = &'a str>, delimiter: char) -> String {
+    fields
+        .into_iter()
+        .map(|f| escape_csv(f, delimiter))
+        .collect::<Vec<_>>()
+        .join(&delimiter.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `rows` rows of a single-note left-down-up-right stream, one measure.
+    fn stream(rows: usize) -> String {
+        ["1000\n", "0100\n", "0010\n", "0001\n"].iter().cycle().take(rows).copied().collect()
+    }
+
+    /// Joins measures into the note data of a `#NOTES` block.
+    fn note_data(measures: &[String]) -> String {
+        measures.join(",\n") + ";\n"
+    }
+
+    fn timing(bpms: &str, time_signatures: &str) -> TimingData {
+        TimingData::parse(&TimingTags {
+            bpms,
+            time_signatures,
+            fakes: "",
+            stops: "",
+            delays: "",
+            display_bpm: "",
+        })
+    }
+
+    /// Measures a breakdown string covers, with the one-measure breaks it
+    /// leaves out between runs added back.
+    fn breakdown_measures(breakdown: &str) -> usize {
+        let tokens: Vec<&str> = breakdown.split(' ').filter(|t| !t.starts_with('[')).collect();
+        let length = |t: &str| t.trim_matches(|c: char| !c.is_ascii_digit()).parse::<usize>().unwrap();
+        let implicit_breaks = tokens
+            .windows(2)
+            .filter(|w| !w[0].starts_with('(') && !w[1].starts_with('('))
+            .count();
+        tokens.iter().map(|t| length(t)).sum::<usize>() + implicit_breaks
+    }
+
+    #[test]
+    fn missing_semicolon_still_parses_both_charts() {
+        // Neither the title nor the first chart has its `;`.
+        let simfile = format!(
+            "#TITLE:No Semicolons\n#BPMS:0.000=120.000;\n#NOTES:\n     dance-single:\n     :\n     Easy:\n     2:\n     :\n{}\n#NOTES:\n     dance-single:\n     :\n     Hard:\n     8:\n     :\n{};\n",
+            stream(4),
+            stream(16),
+        );
+        let data = simfile.as_bytes();
+        let sections = extract_sections(data).unwrap();
+        assert_eq!(sections.title, Some(&b"No Semicolons\n"[..]));
+        assert_eq!(sections.auto_terminated, vec!["#TITLE"]);
+
+        let (fields, easy) = split_notes_fields(sections.notes.unwrap());
+        assert_eq!(fields[2].trim_ascii(), b"Easy");
+        let (_, easy_stats, easy_measures) = minimize_chart_and_count(easy);
+        assert_eq!((easy_stats.total_steps, easy_measures.len()), (4, 1));
+
+        let hard = find_chart(data, "dance-single", "Hard").unwrap().notes;
+        let (_, hard_stats, hard_measures) = minimize_chart_and_count(hard);
+        assert_eq!((hard_stats.total_steps, hard_measures.len()), (16, 1));
+    }
+
+    #[test]
+    fn three_four_measures_are_three_beats_long() {
+        let measures = [stream(16), stream(12), stream(12), stream(16)];
+        let (_, _, densities) = minimize_chart_and_count(note_data(&measures).as_bytes());
+        let timing = timing("0.000=120.000", "0=4=4,4=3=4,10=4=4");
+        let measure_beats = timing.measure_beats(densities.len());
+        assert_eq!(measure_beats, vec![(0.0, 4.0), (4.0, 3.0), (7.0, 3.0), (10.0, 4.0)]);
+        // 16 notes in 2s and 12 notes in 1.5s are the same 8 notes per second.
+        let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+        assert_eq!(nps, vec![8.0; 4]);
+        assert_eq!(compute_total_chart_length(&measure_beats, &timing), 7);
+    }
+
+    #[test]
+    fn only_tagless_input_is_a_bare_notes_block() {
+        assert!(!has_any_tag(stream(16).as_bytes()));
+        assert!(has_any_tag(b"#TITLE:Header Only;\n#ARTIST:A;\n#BPMS:0.000=120.000;\n"));
+        assert!(has_any_tag(b"\xef\xbb\xbf\n  #TITLE:Indented;\n"));
+    }
+
+    #[test]
+    fn nps_at_second_is_zero_outside_the_timing() {
+        let measures = [stream(16), stream(16)];
+        let (_, _, densities) = minimize_chart_and_count(note_data(&measures).as_bytes());
+        let timing = timing("0.000=120.000", "");
+        let measure_beats = timing.measure_beats(densities.len());
+        let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+        assert_eq!(timing.beat_at_second(3.0), Some(6.0));
+        assert_eq!(timing.beat_at_second(f64::INFINITY), None);
+        assert_eq!(self::timing("", "").beat_at_second(1.0), None);
+        assert_eq!(nps_at_second(1.0, 0.0, &timing, &measure_beats, &nps), 8.0);
+        assert_eq!(nps_at_second(f64::INFINITY, 0.0, &timing, &measure_beats, &nps), 0.0);
+        assert_eq!(nps_at_second(4.0, 0.0, &timing, &measure_beats, &nps), 0.0);
+    }
+
+    #[test]
+    fn nps_at_second_is_song_time() {
+        // A silent first measure, then stream from 2.0s of chart time.
+        let measures = ["0000\n".repeat(4), stream(16)];
+        let (_, _, densities) = minimize_chart_and_count(note_data(&measures).as_bytes());
+        let timing = timing("0.000=120.000", "");
+        let measure_beats = timing.measure_beats(densities.len());
+        let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+        // With #OFFSET:-0.5 the stream starts 2.5s into the song.
+        assert_eq!(nps_at_second(2.2, -0.5, &timing, &measure_beats, &nps), 0.0);
+        assert_eq!(nps_at_second(2.7, -0.5, &timing, &measure_beats, &nps), 8.0);
+        assert_eq!(nps_at_second(2.2, 0.0, &timing, &measure_beats, &nps), 8.0);
+        assert_eq!(nps_at_second(1.7, 0.5, &timing, &measure_beats, &nps), 8.0);
+        assert_eq!(nps_at_second(-0.7, 0.5, &timing, &measure_beats, &nps), 0.0);
+    }
+
+    /// The GrooveStats hash of the first chart in `simfile`, as `analyze_file`
+    /// computes it by default.
+    fn chart_hash(simfile: &[u8]) -> String {
+        let mut data = simfile.to_vec();
+        strip_bom(&mut data);
+        let sections = extract_sections(&data).unwrap();
+        let (_, notes) = split_notes_fields(sections.notes.unwrap());
+        let (mut chart, _, _) = minimize_chart_and_count(notes);
+        if let Some(pos) = chart.iter().rposition(|&b| b != b'\n') {
+            chart.truncate(pos + 1);
         }
+        let bpms = std::str::from_utf8(sections.bpms.unwrap()).unwrap();
+        groovestats_hash(&chart, &coalesce_bpm_segments(&normalize_float_digits(bpms)))
+    }
+
+    #[test]
+    fn crlf_and_bom_do_not_change_the_hash() {
+        let simfile = format!(
+            "#TITLE:Line Endings;\n#BPMS:0.000=120.000\n,8.000=150.000;\n#NOTES:\n     dance-single:\n     :\n     Hard:\n     8:\n     :\n{};\n",
+            [stream(16), stream(8), "0000\n".to_string()].join(",  // second measure\n"),
+        );
+        let windows = format!("\u{feff}{}", simfile.replace('\n', "\r\n"));
+        assert!(windows.as_bytes().starts_with(b"\xEF\xBB\xBF"));
+        assert_eq!(chart_hash(windows.as_bytes()), chart_hash(simfile.as_bytes()));
+    }
+
+    #[test]
+    fn empty_notes_block_has_no_measures() {
+        for notes in ["", "\n", "\n\n  \n"] {
+            let (chart, stats, densities) = minimize_chart_and_count(notes.as_bytes());
+            assert!(chart.is_empty());
+            assert!(densities.is_empty());
+            assert_eq!((stats.total_arrows, stats.total_steps), (0, 0));
+
+            let timing = timing("0.000=120.000", "");
+            let measure_beats = timing.measure_beats(densities.len());
+            let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+            assert!(nps.is_empty());
+            assert_eq!(median(&nps), 0.0);
+            assert_eq!(compute_total_chart_length(&measure_beats, &timing), 0);
+        }
+    }
+
+    #[test]
+    fn groovestats_hash_matches_a_pinned_chart() {
+        // Eighth-note rows that minimize to quarters, then a jump measure.
+        let simfile = b"#TITLE:Pinned;\n#BPMS:0=120,4=150;\n#NOTES:\n     dance-single:\n     :\n     Easy:\n     3:\n     :\n1000\n0000\n0100\n0000\n0010\n0000\n0001\n0000\n,\n1001\n0000\n0000\n0000\n;\n";
+        // sha1("1000\n0100\n0010\n0001\n,\n1001" + "0.000=120.000,4.000=150.000"),
+        // computed outside rssp.
+        assert_eq!(chart_hash(simfile), "59b99085e5e5bc92e19a270e029a5240b71d0959");
+    }
+
+    #[test]
+    fn duplicate_beat_bpms_collapse_last_one_wins() {
+        assert_eq!(normalize_float_digits("0=120,0=150,8=180"), "0.000=150.000,8.000=180.000");
+        assert_eq!(normalize_float_digits("0=120,8=180,0.0001=150"), "0.000=150.000,8.000=180.000");
+        let simfile = |bpms: &str| {
+            format!(
+                "#BPMS:{};\n#NOTES:\n     dance-single:\n     :\n     Hard:\n     8:\n     :\n{};\n",
+                bpms,
+                note_data(&[stream(16), stream(16), stream(16)]).trim_end_matches(";\n"),
+            )
+        };
+        let duplicated = chart_hash(simfile("0=120,0=150,8=180").as_bytes());
+        assert_eq!(duplicated, chart_hash(simfile("0=150,8=180").as_bytes()));
+        assert_ne!(duplicated, chart_hash(simfile("0=120,8=180").as_bytes()));
+    }
+
+    #[test]
+    fn timed_arrow_stats_cover_the_whole_chart() {
+        // A guide mine in the first measure and a fake region over the second;
+        // `--measures 2:3` used to report neither.
+        let measures = ["1000\n0100\n0010\n000M\n".to_string(), "0001\n".repeat(4), stream(4)];
+        let (chart, mut stats, densities) = minimize_chart_and_count(note_data(&measures).as_bytes());
+        let timing = TimingData::parse(&TimingTags {
+            bpms: "0.000=120.000",
+            time_signatures: "",
+            fakes: "4.000=4.000",
+            stops: "",
+            delays: "",
+            display_bpm: "",
+        });
+        count_timed_arrow_stats(&mut stats, &chart, &timing, densities.len());
+        assert_eq!((stats.faked_notes, stats.guide_mines), (4, 1));
+    }
+
+    #[test]
+    fn custom_patterns_match_rows_and_validate_repeats() {
+        let lr = parse_custom_pattern("L.R").unwrap();
+        // Left and right, with or without a step between them.
+        assert_eq!(count_custom_pattern(&[0b0001, 0, 0b1000], &lr), 1);
+        assert_eq!(count_custom_pattern(&[0b0001, 0b0110, 0b1000], &lr), 1);
+        assert_eq!(count_custom_pattern(&[0b0001, 0b0010, 0b0100, 0b1000], &lr), 0);
+        assert_eq!(parse_custom_pattern("*{3}").unwrap(), vec![PatternAtom::AnySingle; 3]);
+        assert!(parse_custom_pattern("L{3").is_err());
+        assert!(parse_custom_pattern("L{0}").is_err());
+        assert!(parse_custom_pattern("L{257}").is_err());
+        assert!(parse_custom_pattern("L{99999999999}").is_err());
+    }
+
+    #[test]
+    fn json_numbers_keep_their_precision() {
+        let report = json!({ "max_nps": json_fixed(10.0, 3), "second": json_fixed(f64::INFINITY, 2) });
+        assert_eq!(serde_json::to_string(&report).unwrap(), r#"{"max_nps":10.000,"second":null}"#);
+        assert_eq!(serde_json::to_string_pretty(&report).unwrap(), "{\n  \"max_nps\": 10.000,\n  \"second\": null\n}");
+    }
+
+    #[test]
+    fn final_measure_span_ignores_row_resolution() {
+        // One tap at the start of the last measure, written with 4 and 192 rows.
+        let max_nps = |last: String| {
+            let chart = note_data(&[stream(16), last]);
+            let (_, _, densities) = minimize_chart_and_count(chart.as_bytes());
+            let timing = timing("0.000=150.000", "");
+            let mut measure_beats = timing.measure_beats(densities.len());
+            let last = measure_beats.last_mut().unwrap();
+            last.1 = final_measure_span(chart.as_bytes(), last.1);
+            let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+            (nps[1], get_nps_stats(&nps).0)
+        };
+        let quarters = max_nps(format!("1000\n{}", "0000\n".repeat(3)));
+        let fine = max_nps(format!("1000\n{}", "0000\n".repeat(191)));
+        // A one-beat floor: 1 note in 0.4 seconds.
+        assert_eq!(quarters, (2.5, 10.0));
+        assert_eq!(fine, quarters);
+        assert_eq!(final_measure_span(stream(16).as_bytes(), 4.0), 4.0);
+    }
+
+    #[test]
+    fn timing_data_parses_every_tag() {
+        let timing = TimingData::parse(&TimingTags {
+            bpms: "32.000=180.000,0.000=150.000",
+            time_signatures: "0=4=4, 16=7=8, 19.5=3=4",
+            fakes: "8.000=2.000",
+            stops: "4.000=0.500,2.000=0.000",
+            delays: "12.000=0.250",
+            display_bpm: "",
+        });
+        assert_eq!(timing.bpm_map, vec![(0.0, 150.0), (32.0, 180.0)]);
+        assert_eq!(timing.time_signatures, vec![(0.0, 4.0), (16.0, 3.5), (19.5, 3.0)]);
+        assert_eq!(timing.fakes, vec![(8.0, 2.0)]);
+        assert_eq!(timing.stops, vec![(4.0, 0.5)]);
+        assert_eq!(timing.delays, vec![(12.0, 0.25)]);
+    }
+
+    #[test]
+    fn find_chart_matches_difficulty_aliases() {
+        let simfile = format!(
+            "#BPMS:0=120;\n#NOTES:\n     dance-single:\n     :\n     Expert:\n     12:\n     :\n{};\n#NOTES:\n     dance-single:\n     :\n     Maniac:\n     9:\n     :\n{};\n",
+            stream(4),
+            stream(8),
+        );
+        let data = simfile.as_bytes();
+        let fields = find_chart(data, "dance-single", "Challenge").unwrap().fields;
+        assert_eq!(fields[3].trim_ascii(), b"12");
+        let fields = find_chart(data, "DANCE-SINGLE", " hard ").unwrap().fields;
+        assert_eq!(fields[3].trim_ascii(), b"9");
+        assert!(find_chart(data, "dance-single", "Edit").is_none());
+    }
+
+    #[test]
+    fn hold_and_roll_tails_are_not_counted() {
+        let mut stats = ArrowStats::default();
+        for row in [b"2004", b"3003", b"4020", b"3030"] {
+            count_line(row, &mut stats);
+        }
+        assert_eq!((stats.holds, stats.rolls), (2, 2));
+        assert_eq!((stats.total_steps, stats.jumps, stats.total_arrows), (2, 2, 4));
+    }
+
+    #[test]
+    fn repeated_bpm_segments_hash_like_one() {
+        let merged = coalesce_bpm_segments(&normalize_float_digits("0=120,4=120,8=120"));
+        assert_eq!(merged, "0.000=120.000");
+        assert_eq!(coalesce_bpm_segments("0.000=120.000,4.000=150.000,8.000=120.000"), "0.000=120.000,4.000=150.000,8.000=120.000");
+        let simfile = |bpms: &str| {
+            format!(
+                "#BPMS:{};\n#NOTES:\n     dance-single:\n     :\n     Hard:\n     8:\n     :\n{};\n",
+                bpms,
+                stream(16),
+            )
+        };
+        assert_eq!(chart_hash(simfile("0=120,4=120,8=120").as_bytes()), chart_hash(simfile("0=120").as_bytes()));
+    }
+
+    #[test]
+    fn display_bpm_rounds_and_honors_the_tag() {
+        assert_eq!(timing("0.000=150.000", "").display_bpm_string(), "150");
+        assert_eq!(timing("0.000=100.000,16.000=200.000", "").display_bpm_string(), "100-200");
+        assert_eq!(timing("0.000=149.500", "").display_bpm_string(), "150");
+        assert_eq!(timing("0.000=149.500,8.000=200.250", "").display_bpm_string(), "150-200");
+
+        let with_tag = |display_bpm: &str| {
+            TimingData::parse(&TimingTags {
+                bpms: "0.000=100.000,16.000=200.000",
+                time_signatures: "",
+                fakes: "",
+                stops: "",
+                delays: "",
+                display_bpm,
+            })
+            .display_bpm_string()
+        };
+        assert_eq!(with_tag("150"), "150");
+        assert_eq!(with_tag("90:180.4"), "90-180");
+        assert_eq!(with_tag("*"), "100-200");
+        assert_eq!(with_tag(""), "100-200");
+    }
+
+    #[test]
+    fn zero_length_stop_changes_nothing() {
+        let with_stops = |stops: &str| {
+            TimingData::parse(&TimingTags {
+                bpms: "0.000=120.000",
+                time_signatures: "",
+                fakes: "",
+                stops,
+                delays: "",
+                display_bpm: "",
+            })
+        };
+        let zero = with_stops("4.000=0.000");
+        let none = with_stops("");
+        assert!(zero.stops.is_empty());
+        let measure_beats = none.measure_beats(4);
+        assert_eq!(
+            compute_total_chart_length(&zero.measure_beats(4), &zero),
+            compute_total_chart_length(&measure_beats, &none),
+        );
+        assert_eq!(zero.second_at_beat(8.0), none.second_at_beat(8.0));
+    }
+
+    #[test]
+    fn keysounds_and_lifts_are_not_steps() {
+        let mut stats = ArrowStats::default();
+        for row in [b"1000", b"K000", b"0K0K", b"0L00", b"L00L"] {
+            count_line(row, &mut stats);
+        }
+        assert_eq!((stats.total_steps, stats.total_arrows), (1, 1));
+        assert_eq!(stats.lifts, 3);
+    }
+
+    #[test]
+    fn notedata_charts_use_their_own_timing() {
+        let simfile = format!(
+            "#VERSION:0.83;\n#TITLE:Split Timing;\n#BPMS:0.000=150.000;\n#OFFSET:0.000;\n#STOPS:;\n\
+             #NOTEDATA:;\n#STEPSTYPE:dance-single;\n#DESCRIPTION:;\n#DIFFICULTY:Easy;\n#METER:3;\n#NOTES:\n{};\n\
+             #NOTEDATA:;\n#STEPSTYPE:dance-double;\n#DESCRIPTION:;\n#DIFFICULTY:Hard;\n#METER:11;\n\
+             #BPMS:0.000=300.000;#OFFSET:-1.0;\n#NOTES:\n{};\n",
+            stream(4),
+            stream(16),
+        );
+        let data = simfile.as_bytes();
+        let mut sections = extract_sections(data).unwrap();
+        let first = sections.notedata.clone().unwrap();
+        assert_eq!(first.fields[0], b"dance-single");
+        assert_eq!((first.fields[2], first.fields[3]), (&b"Easy"[..], &b"3"[..]));
+        assert!(extract_sections(first.chart_tags).unwrap().bpms.is_none());
+
+        let chart = find_chart(data, "dance-double", "Hard").unwrap();
+        assert_eq!(chart.fields[0], b"dance-double");
+        assert_eq!((chart.fields[2], chart.fields[3]), (&b"Hard"[..], &b"11"[..]));
+        sections.apply_chart_timing(&extract_sections(chart.chart_tags).unwrap());
+        assert_eq!(sections.bpms, Some(&b"0.000=300.000"[..]));
+        assert_eq!(sections.offset, Some(&b"-1.0"[..]));
+        assert_eq!(sections.title, Some(&b"Split Timing"[..]));
+    }
+
+    #[test]
+    fn orphan_tail_gap_starts_at_the_main_body() {
+        let orphan_tail = |densities: &[usize]| {
+            let timing = timing("0.000=120.000", "");
+            find_orphan_tail(densities, &timing.measure_beats(densities.len()))
+        };
+        let body = [16; 4];
+        let chart = |tail: &[usize]| [&body[..], tail].concat();
+        // A stray note 20 measures out, then another shortly after.
+        let split_tail = chart(&[[0; 20].as_slice(), &[1, 0, 0, 0, 1]].concat());
+        assert_eq!(orphan_tail(&split_tail), Some(80.0));
+        // One right after the body, the other 20 measures later.
+        let late_tail = chart(&[[1].as_slice(), &[0; 20], &[1]].concat());
+        assert_eq!(orphan_tail(&late_tail), Some(80.0));
+        assert_eq!(orphan_tail(&chart(&[[0; 10].as_slice(), &[1]].concat())), None);
+        // Too many notes out there to be stray.
+        assert_eq!(orphan_tail(&chart(&[[0; 20].as_slice(), &[4, 4, 4]].concat())), None);
+    }
+
+    #[test]
+    fn bpms_after_notes_still_apply() {
+        let notes = format!("#NOTES:\n     dance-single:\n     :\n     Hard:\n     9:\n     :\n{};\n", stream(4));
+        let bpms = "#BPMS:0.000=150.000,8.000=300.000;\n";
+        let late = format!("#TITLE:Late;\n{notes}{bpms}");
+        let sections = extract_sections(late.as_bytes()).unwrap();
+        assert_eq!(sections.bpms, Some(&b"0.000=150.000,8.000=300.000"[..]));
+        let timing = timing(std::str::from_utf8(sections.bpms.unwrap()).unwrap(), "");
+        assert_eq!(timing.bpm_map, vec![(0.0, 150.0), (8.0, 300.0)]);
+        let early = format!("#TITLE:Late;\n{bpms}{notes}");
+        assert_eq!(chart_hash(late.as_bytes()), chart_hash(early.as_bytes()));
+    }
+
+    #[test]
+    fn huge_bpm_warps_keep_stats_finite() {
+        let measures = [stream(16), stream(16), stream(16)];
+        let chart = note_data(&measures);
+        let (_, _, densities) = minimize_chart_and_count(chart.as_bytes());
+        let timing = timing("0.000=150.000,4.000=999999.000,8.000=150.000", "");
+        let measure_beats = timing.measure_beats(densities.len());
+        let nps = compute_measure_nps_vec(&densities, &measure_beats, &timing.bpm_map);
+        assert_eq!(nps, vec![10.0, 0.0, 10.0]);
+        let (max_nps, median_nps) = get_nps_stats(&nps);
+        assert!(max_nps.is_finite() && median_nps.is_finite());
+        let stamina = compute_stamina_score(&nps, &measure_beats, &timing.bpm_map);
+        assert!(stamina.is_finite() && stamina < 10.0);
+        let rows = collect_note_rows(chart.as_bytes(), &measure_beats);
+        assert_eq!(compute_notes_weighted_bpm(&rows, &timing.bpm_map), 150.0);
+        assert_eq!(compute_bpm_range(&timing.bpm_map), (150, 150));
+        assert_eq!(format_bpm(999999.0), "warp");
+    }
+
+    #[test]
+    fn negative_bpms_and_stops_warp_alike() {
+        let parse = |bpms: &str, stops: &str| {
+            TimingData::parse(&TimingTags {
+                bpms,
+                time_signatures: "",
+                fakes: "",
+                stops,
+                delays: "",
+                display_bpm: "",
+            })
+        };
+        let chart_length = |timing: &TimingData| compute_total_chart_length(&timing.measure_beats(4), timing);
+        // Both skip beats 4 to 6 at 120 BPM.
+        let negative_bpm = parse("0.000=120.000,4.000=-120.000,5.000=120.000", "");
+        let negative_stop = parse("0.000=120.000", "4.000=-1.000");
+        assert_eq!(negative_bpm.bpm_map, vec![(0.0, 120.0), (4.0, WARP_SEGMENT_BPM), (6.0, 120.0)]);
+        assert_eq!(negative_stop.bpm_map, negative_bpm.bpm_map);
+        assert!(negative_stop.stops.is_empty());
+        assert_eq!(chart_length(&negative_bpm), 6);
+        assert_eq!(chart_length(&negative_stop), chart_length(&negative_bpm));
+
+        // A 0 BPM segment outside the warp survives it.
+        let paused = parse("0.000=120.000,8.000=0.000,9.000=120.000", "4.000=-1.000");
+        assert_eq!(
+            paused.bpm_map,
+            vec![(0.0, 120.0), (4.0, WARP_SEGMENT_BPM), (6.0, 120.0), (8.0, 0.0), (9.0, 120.0)]
+        );
+    }
+
+    #[test]
+    fn simfile_charts_lists_every_chart() {
+        let simfile = format!(
+            "#TITLE:Two Charts;\n#BPMS:0=120;\n#NOTES:\n     dance-single:\n     :\n     Easy:\n     3:\n     :\n{};\n\
+             #NOTES:\n     dance-single:\n     :\n     Hard:\n     9:\n     :\n{};\n",
+            stream(4),
+            stream(16),
+        );
+        let charts = simfile_charts(simfile.as_bytes());
+        let counts: Vec<(&str, u32)> = charts
+            .iter()
+            .map(|chart| (chart.field(2), minimize_chart_and_count(chart.notes).1.total_steps))
+            .collect();
+        assert_eq!(counts, vec![("Easy", 4), ("Hard", 16)]);
+        assert!(simfile_charts(b"#TITLE:No Charts;\n").is_empty());
+    }
+
+    #[test]
+    fn tagged_file_without_notes_is_an_error() {
+        let header = b"#TITLE:Header Only;\n#ARTIST:A;\n#OFFSET:0.000;\n#BPMS:0.000=120.000;\n";
+        let sections = extract_sections(header).unwrap();
+        let err = select_chart(header, &sections, None).err().unwrap();
+        assert_eq!(err.to_string(), "#NOTES section is incomplete.");
+
+        let bare = stream(16);
+        let sections = extract_sections(bare.as_bytes()).unwrap();
+        let chart = select_chart(bare.as_bytes(), &sections, None).unwrap();
+        assert_eq!((chart.field(0), chart.field(2)), ("dance-single", "Edit"));
+        assert_eq!(chart.notes, bare.as_bytes());
+    }
+
+    #[test]
+    fn notes_weighted_bpm_counts_every_note_of_a_jump() {
+        // Four jumps at 120 BPM, then four taps at 240 BPM.
+        let chart = note_data(&["1001\n".repeat(4), stream(4)]);
+        let timing = timing("0.000=120.000,4.000=240.000", "");
+        let rows = collect_note_rows(chart.as_bytes(), &timing.measure_beats(2));
+        // By row this would be 180.
+        assert_eq!(compute_notes_weighted_bpm(&rows, &timing.bpm_map), 160.0);
+    }
+
+    #[test]
+    fn custom_patterns_ignore_measure_quantization() {
+        // Quarter-note LDUR in both measures; the second also has a 16th tap
+        // after its R, so it is written with 16 rows instead of 4.
+        let second = "1000\n0000\n0000\n0000\n0100\n0000\n0000\n0000\n\
+                      0010\n0000\n0000\n0000\n0001\n0000\n0000\n1000\n";
+        let chart = note_data(&[stream(4), second.to_string()]);
+        let (minimized, _, _) = minimize_chart_and_count(chart.as_bytes());
+        let bitmasks = parse_bitmask_chart(&minimized);
+        assert_eq!(count_custom_pattern(&bitmasks, &parse_custom_pattern("LDUR").unwrap()), 2);
+        assert_eq!(count_custom_pattern(&bitmasks, &parse_custom_pattern("R.L").unwrap()), 2);
+        assert_eq!(count_custom_pattern(&bitmasks, &parse_custom_pattern("*{4}").unwrap()), 6);
+    }
+
+    #[test]
+    fn json_layout_keeps_the_classic_shape() {
+        let report = json!({
+            "title": "A \u{1} B",
+            "bpm_info": { "max_nps": json_fixed(12.0, 2), "offset": json_number(3.0) },
+            "stream_sequences": [{ "stream_start": 2, "is_break": false }],
+            "suspicious_jumps": [],
+        });
+        assert_eq!(
+            json_layout(&report),
+            "{\n  \"title\": \"A \\u0001 B\",\n  \"bpm_info\": {\n     \"max_nps\": 12.00,\n     \"offset\": 3\n  },\n  \
+             \"stream_sequences\": [{ \"stream_start\": 2, \"is_break\": false }],\n  \"suspicious_jumps\": []\n}"
+        );
+    }
+
+    #[test]
+    fn duplicate_charts_point_at_their_copy() {
+        let chart = |difficulty: &str, notes: String| {
+            format!("#NOTES:\n     dance-single:\n     :\n     {}:\n     9:\n     :\n{};\n", difficulty, notes)
+        };
+        // The Challenge chart repeats the Hard one, written with 8 rows a measure.
+        let doubled: String = stream(16).lines().map(|row| format!("{}\n0000\n", row)).collect();
+        let simfile = [
+            "#BPMS:0=120;\n".to_string(),
+            chart("Easy", stream(4)),
+            chart("Hard", stream(16)),
+            chart("Challenge", doubled),
+        ]
+        .concat();
+        let charts = simfile_charts(simfile.as_bytes());
+        assert_eq!(find_duplicate_chart(&charts, &charts[0]), None);
+        assert_eq!(find_duplicate_chart(&charts, &charts[1]), Some(2));
+        assert_eq!(find_duplicate_chart(&charts, &charts[2]), Some(1));
+    }
+
+    #[test]
+    fn dwi_steps_holds_and_chords() {
+        let rows = parse_dwi_steps(b"2!24<68>(20)1");
+        let rows: Vec<(usize, &[u8])> = rows.iter().map(|(&row, line)| (row, &line[..])).collect();
+        assert_eq!(
+            rows,
+            vec![
+                (0, &b"0200"[..]),
+                (24, &b"1000"[..]),
+                (48, &b"0011"[..]),
+                (72, &b"0300"[..]),
+                (96, &b"1100"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dwi_freezes_become_stops() {
+        let sm = dwi_to_sm(b"#TITLE:Old;\n#BPM:120;\n#CHANGEBPM:32=240;\n#FREEZE:16=500,48=250;\n#SINGLE:BASIC:3:2!24;\n");
+        let sm = String::from_utf8(sm).unwrap();
+        assert!(sm.contains("#BPMS:0.000=120,8.000=240;\n"));
+        assert!(sm.contains("#STOPS:4.000=0.500,12.000=0.250;\n"));
+    }
+
+    #[test]
+    fn hardest_chart_ranks_difficulty_then_meter() {
+        let chart = |difficulty: &str, meter: &str| {
+            format!("#NOTES:\n     dance-single:\n     :\n     {}:\n     {}:\n     :\n{};\n", difficulty, meter, stream(4))
+        };
+        let simfile = [
+            chart("Easy", "3"),
+            chart("Challenge", "9"),
+            chart("Hard", "12"),
+            chart("Expert", "11"),
+            chart("Challenge", "11"),
+            chart("Beginner", "?"),
+        ]
+        .concat();
+        let hardest = hardest_chart(simfile_charts(simfile.as_bytes())).unwrap();
+        // Expert is a Challenge too, and ties keep the earlier chart.
+        assert_eq!((hardest.field(2), hardest.field(3)), ("Expert", "11"));
+        assert!(hardest_chart(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn note_rows_round_trip_on_both_sides_of_zero() {
+        assert_eq!(beat_to_note_row(2.9999999), 144);
+        assert_eq!(beat_to_note_row(0.25), 12);
+        assert_eq!(beat_to_note_row(-1.0), -48);
+        assert_eq!(beat_to_note_row(-0.0001), 0);
+        assert_eq!(beat_to_note_row(-2.5), -120);
+        for row in [-192, -13, 0, 7, 191] {
+            assert_eq!(beat_to_note_row(note_row_to_beat(row)), row);
+        }
+        assert_eq!(note_row_to_beat(-24), -0.5);
+    }
+
+    #[test]
+    fn odd_meter_breakdown_counts_every_note_block() {
+        // Four 4/4 stream measures, then a 3/4 break with a BPM change in it
+        // (beat 19 is the second 3/4 measure), then 4/4 stream again.
+        let empty = "0000\n".to_string();
+        let measures = [stream(16), stream(16), stream(16), stream(16), empty.clone(), empty.clone(), empty, stream(16)];
+        let (_, _, densities) = minimize_chart_and_count(note_data(&measures).as_bytes());
+        assert_eq!(densities.len(), measures.len());
+
+        let timing = timing("0.000=120.000,19.000=180.000", "0=4=4,16=3=4,25=4=4");
+        let measure_beats = timing.measure_beats(densities.len());
+        assert_eq!(measure_beats[4], (16.0, 3.0));
+        assert_eq!(measure_beats[5], (19.0, 3.0));
+        assert_eq!(measure_beats[7], (25.0, 4.0));
+
+        let detailed = generate_breakdown(&densities, BreakdownMode::Detailed, &DEFAULT_STREAM_THRESHOLDS);
+        let annotated =
+            generate_annotated_breakdown(&densities, &measure_beats, &timing.bpm_map, &DEFAULT_STREAM_THRESHOLDS);
+        assert_eq!(detailed, "4 (3) 1");
+        assert_eq!(annotated, "[120] 4 (3) [180] 1");
+        assert_eq!("annotated".parse::<BreakdownMode>(), Ok(BreakdownMode::Annotated));
+        assert_eq!(breakdown_measures(&detailed), measures.len());
+        assert_eq!(breakdown_measures(&annotated), measures.len());
     }
-    out
 }